@@ -1,4 +1,11 @@
 pub mod user;
 pub mod contacts;
 pub mod msgs;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod rooms;
+pub mod search;
+pub mod attachments;
+pub mod scheduled;
+pub mod stars;
+pub mod drafts;
+pub mod export;
\ No newline at end of file
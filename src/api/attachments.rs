@@ -0,0 +1,214 @@
+use std::{fs, io, path::{Path, PathBuf}, time::{SystemTime, UNIX_EPOCH}};
+
+use actix_session::Session;
+use actix_web::{error, get, http::header::{self, ContentDisposition, DispositionParam, DispositionType}, post, web, HttpRequest, HttpResponse, Responder};
+use futures::StreamExt;
+use log::warn;
+use rusqlite::{params, OptionalExtension, Transaction};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use self::voice::VoiceNote;
+
+use crate::{api::auth::validate_session, db::{self, Pool}, ws::{policy::can_access_attachment, Attachment, MessagingPolicy}};
+
+mod voice;
+
+const ATTACHMENTS_DIR: &str = "data/attachments";
+const DEFAULT_MIME: &str = "application/octet-stream";
+const MAX_MIME_LENGTH: usize = 128;
+const MAX_NAME_LENGTH: usize = 255;
+/// Types a browser can show without running anything in them, every other file is downloaded
+const INLINE_MIMES: &[&str] = &["image/png", "image/jpeg", "image/gif", "image/webp", "audio/", "video/"];
+
+/// Files are stored by the hash of their content, so the same file is only stored once
+fn attachment_path(hash: &str) -> PathBuf {
+    PathBuf::from(ATTACHMENTS_DIR).join(hash)
+}
+
+/// Forgets the attachments that aren't in any message anymore, nor waiting to be sent in a scheduled one.
+/// Returns the files nothing else points to, to be removed before the transaction is done,
+/// while no upload can add a row for them
+pub fn delete_unused(conn: &Transaction, ids: &[i64]) -> Result<Vec<String>, rusqlite::Error> {
+    let mut files = Vec::new();
+
+    for id in ids {
+        let hash: Option<String> = conn.query_row(
+            "DELETE FROM attachments WHERE id = ?1
+            AND NOT EXISTS (SELECT 1 FROM msg_attachments WHERE attachment_id = ?1)
+            AND NOT EXISTS (SELECT 1 FROM scheduled_msgs, json_each(scheduled_msgs.attachments) WHERE json_each.value = ?1)
+            RETURNING hash",
+            params![id],
+            |row| row.get(0)
+        ).optional()?;
+
+        // The same file may have been uploaded again
+        if let Some(hash) = hash {
+            let used = conn.query_row("SELECT 1 FROM attachments WHERE hash = ?1", params![hash], |_| Ok(())).optional()?;
+            if used.is_none() {
+                files.push(hash);
+            }
+        }
+    }
+
+    Ok(files)
+}
+
+pub fn remove_files(hashes: Vec<String>) {
+    for hash in hashes {
+        if let Err(err) = fs::remove_file(attachment_path(&hash)) {
+            warn!("Couldn't remove the attachment {hash}: {err}");
+        }
+    }
+}
+
+/// Writes the file unless it's there already
+fn store_file(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    if path.exists() {
+        return Ok(());
+    }
+
+    // Written aside and then moved, so there's never half a file with the final name
+    fs::create_dir_all(ATTACHMENTS_DIR)?;
+    let partial = path.with_extension("part");
+    fs::write(&partial, bytes)?;
+    fs::rename(partial, path)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryUpload {
+    name: Option<String>,
+    /// It's a recorded voice note, to be shown with its duration and waveform
+    #[serde(default)]
+    voice: bool,
+}
+
+/// The body is the file itself, and its `Content-Type` header the type of the file
+#[post("/upload")]
+pub async fn upload(session: Session, db: web::Data<Pool>, policy: web::Data<MessagingPolicy>, req: HttpRequest, mut payload: web::Payload, query: web::Query<QueryUpload>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let QueryUpload { name, voice } = query.into_inner();
+    let name = name.filter(|name| !name.trim().is_empty());
+    if name.as_ref().is_some_and(|name| name.len() > MAX_NAME_LENGTH) {
+        return Err(error::ErrorBadRequest("The file name is too long"));
+    }
+
+    let mime = req.headers().get(header::CONTENT_TYPE)
+        .and_then(|mime| mime.to_str().ok())
+        .map(|mime| mime.split(';').next().unwrap_or_default().trim().to_lowercase())
+        .filter(|mime| !mime.is_empty())
+        .unwrap_or(DEFAULT_MIME.to_string());
+    if mime.len() > MAX_MIME_LENGTH {
+        return Err(error::ErrorBadRequest("That isn't a valid type"));
+    }
+
+    // The size is checked while reading, so a big file is never kept in memory
+    let mut bytes = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if (bytes.len() + chunk.len()) as u64 > policy.max_attachment_size {
+            return Err(error::ErrorPayloadTooLarge("The file is too big"));
+        }
+        bytes.extend_from_slice(&chunk);
+    }
+    if bytes.is_empty() {
+        return Err(error::ErrorBadRequest("No file uploaded"));
+    }
+
+    let (width, height) = match mime.starts_with("image/") {
+        true => imagesize::blob_size(&bytes)
+            .map(|size| (Some(size.width as u32), Some(size.height as u32)))
+            .map_err(|_| error::ErrorBadRequest("That image couldn't be read"))?,
+        false => (None, None),
+    };
+
+    let voice = match voice {
+        true => Some(voice::is_voice(&mime)
+            .then(|| voice::parse(&mime, &bytes))
+            .flatten()
+            .ok_or_else(|| error::ErrorBadRequest("Voice notes have to be Opus in an Ogg or WebM file"))?),
+        false => None,
+    };
+
+    let hash = format!("{:x}", Sha256::digest(&bytes));
+    let size = bytes.len() as u64;
+
+    let path = attachment_path(&hash);
+    let bytes = bytes.freeze();
+    let (file, contents) = (path.clone(), bytes.clone());
+    web::block(move || store_file(&file, &contents))
+        .await?
+        .map_err(|_| error::ErrorInternalServerError("Couldn't save the file in the server"))?;
+
+    let attachment = db::execute(&db, move |conn| {
+        let (duration, waveform) = match voice {
+            Some(VoiceNote { duration, waveform }) => (Some(duration), Some(waveform)),
+            None => (None, None),
+        };
+
+        let id = conn.query_row(
+            "INSERT INTO attachments (hash, mime, size, width, height, name, uploader, created, duration, waveform)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10) RETURNING (id)",
+            params![
+                hash,
+                mime,
+                size,
+                width,
+                height,
+                name,
+                user_id,
+                SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64,
+                duration,
+                waveform.as_ref().map(|waveform| serde_json::to_string(waveform).unwrap())
+            ],
+            |row| row.get(0)
+        )?;
+
+        Ok(Attachment { id, mime, size, width, height, name, duration, waveform })
+    }).await?;
+
+    // The sweeper may have removed the same file before the row was added, it's never removed once the row is there
+    web::block(move || store_file(&path, &bytes))
+        .await?
+        .map_err(|_| error::ErrorInternalServerError("Couldn't save the file in the server"))?;
+
+    Ok(web::Json(attachment))
+}
+
+#[get("/attachment/{id}")]
+pub async fn download(session: Session, db: web::Data<Pool>, id: web::Path<i64>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let id = id.into_inner();
+
+    let attachment: Option<(String, String, Option<String>)> = db::execute(&db, move |conn| {
+        // Only the uploader and the ones who can see a message it was sent in
+        if !can_access_attachment(conn, &user_id, id)? {
+            return Ok(None);
+        }
+
+        conn.query_row(
+            "SELECT hash, mime, name FROM attachments WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))
+        ).optional()
+    }).await?;
+
+    // It's the same for files that don't exist and for the ones the user can't see
+    let (hash, mime, name) = attachment.ok_or_else(|| error::ErrorNotFound("That attachment doesn't exist"))?;
+
+    let bytes = web::block(move || fs::read(attachment_path(&hash)))
+        .await?
+        .map_err(|_| error::ErrorInternalServerError("Couldn't read the file from the server"))?;
+
+    let inline = INLINE_MIMES.iter().any(|safe| mime == *safe || (safe.ends_with('/') && mime.starts_with(safe)));
+    let disposition = ContentDisposition {
+        disposition: if inline { DispositionType::Inline } else { DispositionType::Attachment },
+        parameters: name.map(DispositionParam::Filename).into_iter().collect(),
+    };
+
+    Ok(HttpResponse::Ok()
+        .content_type(mime)
+        .insert_header(disposition)
+        .insert_header((header::X_CONTENT_TYPE_OPTIONS, "nosniff"))
+        .body(bytes))
+}
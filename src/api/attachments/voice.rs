@@ -0,0 +1,409 @@
+//! Voice notes recorded as Opus, in an Ogg or a WebM container. The audio is never decoded:
+//! the duration comes from the container, and the waveform from the size of the packets,
+//! which grows with how much sound there is in them.
+
+/// What a client needs to show a voice note before downloading it
+#[derive(Debug, Clone)]
+pub struct VoiceNote {
+    /// In milliseconds
+    pub duration: u64,
+    /// How loud each part of the note is, from 0 to [`WAVEFORM_MAX`]
+    pub waveform: Vec<u8>,
+}
+
+const WAVEFORM_LENGTH: usize = 64;
+const WAVEFORM_MAX: u8 = 100;
+/// Opus always works at 48 kHz, whatever the rate of the recording
+const OPUS_RATE: u64 = 48_000;
+/// Enough of the first packet of an Ogg stream to read its header
+const HEAD_BYTES: usize = 19;
+/// Longest note taken, in samples. The container says how long it is, and it can say anything
+const MAX_SAMPLES: u64 = 6 * 60 * 60 * OPUS_RATE;
+
+/// A packet of audio, with the sample where it starts
+struct Packet {
+    start: u64,
+    size: usize,
+}
+
+pub fn is_voice(mime: &str) -> bool {
+    matches!(mime, "audio/ogg" | "audio/opus" | "audio/webm")
+}
+
+/// Reads the voice note, or nothing if it isn't Opus in the container its type says
+pub fn parse(mime: &str, bytes: &[u8]) -> Option<VoiceNote> {
+    let (samples, packets) = match mime {
+        "audio/webm" => webm(bytes)?,
+        _ => ogg(bytes)?,
+    };
+    if packets.is_empty() || samples > MAX_SAMPLES {
+        return None;
+    }
+
+    Some(VoiceNote {
+        duration: samples * 1000 / OPUS_RATE,
+        waveform: waveform(&packets, samples),
+    })
+}
+
+/// How many samples there are in an Opus packet, as told by its first bytes (RFC 6716, section 3.1)
+fn opus_samples(packet: &[u8]) -> Option<u64> {
+    let toc = *packet.first()?;
+    let config = (toc >> 3) as usize;
+    let frame = match config {
+        0..=11 => [480, 960, 1920, 2880][config % 4],
+        12..=15 => [480, 960][config % 2],
+        _ => [120, 240, 480, 960][config % 4],
+    };
+    let frames = match toc & 0x3 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => (*packet.get(1)? & 0x3F) as u64,
+    };
+
+    Some(frame * frames)
+}
+
+/// Ogg pages (RFC 3533) with an Opus stream (RFC 7845)
+fn ogg(bytes: &[u8]) -> Option<(u64, Vec<Packet>)> {
+    let mut packets = Vec::new();
+    let (mut head, mut size) = (Vec::new(), 0);
+    let mut granule = 0;
+
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let header = bytes.get(pos..pos + 27)?;
+        if &header[0..4] != b"OggS" || header[4] != 0 {
+            return None;
+        }
+        // It's -1 when no packet ends in this page
+        let page_granule = i64::from_le_bytes(header[6..14].try_into().ok()?);
+        if page_granule >= 0 {
+            granule = page_granule as u64;
+        }
+
+        let segments = header[26] as usize;
+        let table = bytes.get(pos + 27..pos + 27 + segments)?;
+        pos += 27 + segments;
+
+        // Packets are split in segments of 255 bytes, the last one is shorter
+        for &lacing in table {
+            let segment = bytes.get(pos..pos + lacing as usize)?;
+            head.extend(segment.iter().take(HEAD_BYTES.saturating_sub(head.len())));
+            size += lacing as usize;
+            pos += lacing as usize;
+
+            if lacing < 255 {
+                packets.push((std::mem::take(&mut head), size));
+                size = 0;
+            }
+        }
+    }
+
+    let mut packets = packets.into_iter();
+    let (opus_head, _) = packets.next()?;
+    if !opus_head.starts_with(b"OpusHead") || opus_head.len() < 12 {
+        return None;
+    }
+    let pre_skip = u16::from_le_bytes([opus_head[10], opus_head[11]]) as u64;
+    if !packets.next()?.0.starts_with(b"OpusTags") {
+        return None;
+    }
+
+    let mut start: u64 = 0;
+    let audio = packets
+        .map(|(head, size)| {
+            let packet = Packet { start, size };
+            start = start.checked_add(opus_samples(&head)?)?;
+            Some(packet)
+        })
+        .collect::<Option<Vec<_>>>()?;
+
+    Some((granule.saturating_sub(pre_skip), audio))
+}
+
+mod ebml {
+    pub const EBML: u64 = 0x1A45DFA3;
+    pub const DOC_TYPE: u64 = 0x4282;
+    pub const SEGMENT: u64 = 0x18538067;
+    pub const INFO: u64 = 0x1549A966;
+    pub const TIMECODE_SCALE: u64 = 0x2AD7B1;
+    pub const DURATION: u64 = 0x4489;
+    pub const TRACKS: u64 = 0x1654AE6B;
+    pub const TRACK_ENTRY: u64 = 0xAE;
+    pub const CODEC_ID: u64 = 0x86;
+    pub const CLUSTER: u64 = 0x1F43B675;
+    pub const TIMECODE: u64 = 0xE7;
+    pub const BLOCK_GROUP: u64 = 0xA0;
+    pub const BLOCK: u64 = 0xA1;
+    pub const SIMPLE_BLOCK: u64 = 0xA3;
+
+    /// Elements made of other elements
+    pub const MASTERS: &[u64] = &[EBML, SEGMENT, INFO, TRACKS, TRACK_ENTRY, CLUSTER, BLOCK_GROUP];
+}
+
+/// Reads a variable length integer, keeping the length marker for element ids.
+/// Returns the value, its length and if all its bits are set, which means an unknown size
+fn vint(bytes: &[u8], pos: usize, marker: bool) -> Option<(u64, usize, bool)> {
+    let first = *bytes.get(pos)?;
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return None;
+    }
+
+    let mut value = if marker { first as u64 } else { first as u64 & (0xFF >> len) };
+    for byte in bytes.get(pos + 1..pos + len)? {
+        value = (value << 8) | *byte as u64;
+    }
+
+    Some((value, len, value == (1 << (7 * len)) - 1))
+}
+
+fn uint(data: &[u8]) -> u64 {
+    data.iter().fold(0, |value, byte| (value << 8) | *byte as u64)
+}
+
+/// WebM files made only of Opus tracks, as recorded by browsers
+fn webm(bytes: &[u8]) -> Option<(u64, Vec<Packet>)> {
+    let (mut doc_type, mut codecs) = (None, Vec::new());
+    let mut scale = 1_000_000;
+    let mut duration = None;
+    let mut cluster = 0;
+    let mut blocks = Vec::new();
+
+    // The elements are read one after the other, going inside the ones that hold others.
+    // That way it doesn't matter that the recorders don't know the size of the segment and its clusters
+    let mut pos = 0;
+    while pos < bytes.len() {
+        let (id, id_len, _) = vint(bytes, pos, true)?;
+        let (size, size_len, unknown) = vint(bytes, pos + id_len, false)?;
+        let start = pos + id_len + size_len;
+
+        if ebml::MASTERS.contains(&id) {
+            pos = start;
+            continue;
+        }
+        if unknown {
+            return None;
+        }
+        let end = start.checked_add(usize::try_from(size).ok()?)?;
+        // Recordings cut short lose their last element, but the rest is still fine
+        let Some(data) = bytes.get(start..end) else {
+            break;
+        };
+
+        match id {
+            ebml::DOC_TYPE => doc_type = Some(data),
+            ebml::CODEC_ID => codecs.push(data),
+            ebml::TIMECODE_SCALE => scale = uint(data),
+            ebml::DURATION => duration = match data.len() {
+                4 => Some(f32::from_be_bytes(data.try_into().ok()?) as f64),
+                8 => Some(f64::from_be_bytes(data.try_into().ok()?)),
+                _ => None,
+            },
+            ebml::TIMECODE => cluster = uint(data),
+            ebml::SIMPLE_BLOCK | ebml::BLOCK => {
+                // Track number, time from the start of the cluster and flags, then the frame
+                let (_, track_len, _) = vint(data, 0, false)?;
+                let relative = i16::from_be_bytes(data.get(track_len..track_len + 2)?.try_into().ok()?);
+                let frame = data.get(track_len + 3..)?;
+                blocks.push((cluster.saturating_add_signed(relative as i64), frame));
+            }
+            _ => (),
+        }
+        pos = end;
+    }
+
+    if doc_type != Some(b"webm".as_slice()) || codecs.is_empty() || codecs.iter().any(|codec| *codec != b"A_OPUS") {
+        return None;
+    }
+
+    // Times are in ticks of `scale` nanoseconds, anything beyond the longest note is taken as broken
+    let to_samples = |time: f64| {
+        let samples = time * scale as f64 * OPUS_RATE as f64 / 1e9;
+        (samples.is_finite() && (0.0..=MAX_SAMPLES as f64).contains(&samples)).then_some(samples as u64)
+    };
+    let packets = blocks.iter()
+        .map(|(time, frame)| Some(Packet { start: to_samples(*time as f64)?, size: frame.len() }))
+        .collect::<Option<Vec<_>>>()?;
+
+    let samples = match duration {
+        Some(duration) => to_samples(duration)?,
+        None => {
+            let (time, frame) = blocks.last()?;
+            to_samples(*time as f64)? + opus_samples(frame)?
+        }
+    };
+
+    Some((samples, packets))
+}
+
+/// The average size of the packets in each part of the note, the biggest one being [`WAVEFORM_MAX`]
+fn waveform(packets: &[Packet], samples: u64) -> Vec<u8> {
+    let length = packets.len().min(WAVEFORM_LENGTH);
+    let mut sums = vec![(0, 0); length];
+    for packet in packets {
+        let i = (packet.start as u128 * length as u128 / samples.max(1) as u128).min(length as u128 - 1) as usize;
+        sums[i].0 += packet.size;
+        sums[i].1 += 1;
+    }
+
+    // Parts without packets of their own sound like the one before
+    let mut level = 0.0;
+    let levels: Vec<f64> = sums.into_iter()
+        .map(|(sum, count)| {
+            if count > 0 {
+                level = sum as f64 / count as f64;
+            }
+            level
+        })
+        .collect();
+
+    let max = levels.iter().copied().fold(0.0, f64::max);
+    levels.into_iter()
+        .map(|level| if max > 0.0 { (level / max * WAVEFORM_MAX as f64).round() as u8 } else { 0 })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An Opus packet of 20 ms, `size` bytes long
+    fn opus_packet(size: usize) -> Vec<u8> {
+        let mut packet = vec![0; size];
+        packet[0] = 1 << 3;
+        packet
+    }
+
+    fn ogg_page(granule: i64, packets: &[Vec<u8>]) -> Vec<u8> {
+        let mut table = Vec::new();
+        for packet in packets {
+            table.extend(std::iter::repeat_n(255, packet.len() / 255));
+            table.push((packet.len() % 255) as u8);
+        }
+
+        let mut page = b"OggS".to_vec();
+        page.extend([0, 0]);
+        page.extend(granule.to_le_bytes());
+        page.extend([0; 12]);
+        page.push(table.len() as u8);
+        page.extend(table);
+        packets.iter().for_each(|packet| page.extend(packet));
+        page
+    }
+
+    fn ogg_file(granule: i64, sizes: &[usize]) -> Vec<u8> {
+        let mut head = b"OpusHead".to_vec();
+        head.extend([1, 1]);
+        head.extend(312u16.to_le_bytes());
+        head.extend([0; 7]);
+
+        let mut file = ogg_page(0, &[head]);
+        file.extend(ogg_page(0, &[b"OpusTags".to_vec()]));
+        file.extend(ogg_page(granule, &sizes.iter().map(|size| opus_packet(*size)).collect::<Vec<_>>()));
+        file
+    }
+
+    fn element(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut element = id.to_vec();
+        element.push(0x01);
+        element.extend(&(data.len() as u64).to_be_bytes()[1..]);
+        element.extend(data);
+        element
+    }
+
+    fn webm_file(codec: &[u8], duration: Option<f64>, cluster: u64, blocks: &[(i16, usize)]) -> Vec<u8> {
+        let mut info = element(&[0x2A, 0xD7, 0xB1], &1_000_000u64.to_be_bytes());
+        if let Some(duration) = duration {
+            info.extend(element(&[0x44, 0x89], &duration.to_be_bytes()));
+        }
+
+        let mut cluster = element(&[0xE7], &cluster.to_be_bytes());
+        for (time, size) in blocks {
+            let mut block = vec![0x81];
+            block.extend(time.to_be_bytes());
+            block.push(0x80);
+            block.extend(opus_packet(*size));
+            cluster.extend(element(&[0xA3], &block));
+        }
+
+        let mut segment = element(&[0x15, 0x49, 0xA9, 0x66], &info);
+        segment.extend(element(&[0x16, 0x54, 0xAE, 0x6B], &element(&[0xAE], &element(&[0x86], codec))));
+        // Recorders don't know how long the segment and the clusters will be
+        segment.extend([0x1F, 0x43, 0xB6, 0x75, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        segment.extend(cluster);
+
+        let mut file = element(&[0x1A, 0x45, 0xDF, 0xA3], &element(&[0x42, 0x82], b"webm"));
+        file.extend([0x18, 0x53, 0x80, 0x67, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        file.extend(segment);
+        file
+    }
+
+    #[test]
+    fn reads_ogg_duration_and_waveform() {
+        let sizes: Vec<usize> = (0..50).map(|i| if i < 25 { 10 } else { 300 }).collect();
+        let note = parse("audio/ogg", &ogg_file(50 * 960 + 312, &sizes)).unwrap();
+
+        assert_eq!(note.duration, 1000);
+        assert_eq!(note.waveform.len(), 50);
+        assert_eq!(note.waveform[0], 3);
+        assert_eq!(note.waveform[49], WAVEFORM_MAX);
+    }
+
+    #[test]
+    fn rejects_broken_ogg() {
+        let file = ogg_file(960 + 312, &[10]);
+        assert!(parse("audio/ogg", &file[..file.len() - 1]).is_none());
+        assert!(parse("audio/ogg", &file[4..]).is_none());
+        assert!(parse("audio/ogg", b"").is_none());
+
+        let mut not_opus = file.clone();
+        not_opus[28..36].copy_from_slice(b"Vorbis!!");
+        assert!(parse("audio/ogg", &not_opus).is_none());
+    }
+
+    #[test]
+    fn rejects_ogg_longer_than_the_longest_note() {
+        assert!(parse("audio/ogg", &ogg_file(i64::MAX, &[10])).is_none());
+        assert!(parse("audio/ogg", &ogg_file((MAX_SAMPLES + 313) as i64, &[10])).is_none());
+        assert!(parse("audio/ogg", &ogg_file(MAX_SAMPLES as i64, &[10])).is_some());
+    }
+
+    #[test]
+    fn reads_webm_duration() {
+        let blocks: Vec<(i16, usize)> = (0..50).map(|i| (i * 20, 100)).collect();
+
+        let note = parse("audio/webm", &webm_file(b"A_OPUS", Some(1000.0), 0, &blocks)).unwrap();
+        assert_eq!(note.duration, 1000);
+        assert_eq!(note.waveform, vec![WAVEFORM_MAX; 50]);
+
+        // Without a duration, it lasts until the end of the last block
+        let note = parse("audio/webm", &webm_file(b"A_OPUS", None, 0, &blocks)).unwrap();
+        assert_eq!(note.duration, 1000);
+    }
+
+    #[test]
+    fn rejects_webm_that_isnt_opus() {
+        assert!(parse("audio/webm", &webm_file(b"A_VORBIS", Some(1000.0), 0, &[(0, 100)])).is_none());
+        assert!(parse("audio/webm", &webm_file(b"A_OPUS", Some(1000.0), 0, &[])).is_none());
+    }
+
+    #[test]
+    fn rejects_webm_with_impossible_times() {
+        assert!(parse("audio/webm", &webm_file(b"A_OPUS", Some(f64::INFINITY), 0, &[(0, 100)])).is_none());
+        assert!(parse("audio/webm", &webm_file(b"A_OPUS", Some(-1.0), 0, &[(0, 100)])).is_none());
+        assert!(parse("audio/webm", &webm_file(b"A_OPUS", Some(1e300), 0, &[(0, 100)])).is_none());
+        assert!(parse("audio/webm", &webm_file(b"A_OPUS", None, u64::MAX, &[(i16::MAX, 100)])).is_none());
+        assert!(parse("audio/webm", &webm_file(b"A_OPUS", Some(1000.0), u64::MAX, &[(i16::MAX, 100)])).is_none());
+    }
+
+    #[test]
+    fn keeps_webm_cut_short() {
+        let blocks: Vec<(i16, usize)> = (0..50).map(|i| (i * 20, 100)).collect();
+        let file = webm_file(b"A_OPUS", None, 0, &blocks);
+
+        let note = parse("audio/webm", &file[..file.len() - 50]).unwrap();
+        assert_eq!(note.duration, 980);
+    }
+}
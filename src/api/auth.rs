@@ -9,7 +9,7 @@ use serde::Deserialize;
 
 use crate::db::{self, Pool};
 
-const USER_ID_KEY: &'static str = "user_id";
+const USER_ID_KEY: &str = "user_id";
 
 #[derive(Deserialize, Debug, Default, Clone)]
 struct LoginData {
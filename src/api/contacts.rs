@@ -3,7 +3,7 @@ use actix_web::{error, get, post, web, Responder};
 use rusqlite::params;
 use serde::{Deserialize, Serialize};
 
-use crate::{api::auth::validate_session, db::{self, Pool}, ws::WsMessage};
+use crate::{api::auth::validate_session, db::{self, Pool}, ws::{Disappearing, Pin, WsMessage, MSG_COLUMNS}};
 
 #[derive(Debug, Deserialize)]
 struct QueryContacts {
@@ -14,7 +14,12 @@ struct QueryContacts {
 #[serde(rename_all = "camelCase")]
 struct ContactPreview {
     name: String,
-    last_msg: Option<WsMessage>
+    last_msg: Option<WsMessage>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<i64>,
+    /// What the user left written in the conversation without sending it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    draft: Option<String>,
 }
 
 #[get("/contacts")]
@@ -23,6 +28,8 @@ pub async fn get_contacts(session: Session, db: web::Data<Pool>, query: web::Que
     let query = query.into_inner();
 
     let contacts: Vec<ContactPreview> = db::execute(&db, move |conn| {
+        let search = format!("%{}%", query.search.unwrap_or_default());
+
         let mut stmt = conn.prepare(
             "SELECT users.username FROM contacts 
             INNER JOIN users ON users.username = user2
@@ -30,34 +37,68 @@ pub async fn get_contacts(session: Session, db: web::Data<Pool>, query: web::Que
         )?;
 
         let response = stmt.query_map(
-            params![user_id, format!("%{}%", query.search.unwrap_or(String::new()))], 
+            params![user_id, search], 
             |row| row.get(0) as Result<String, _>
         )?;
 
-        let mut msg_stmt = conn.prepare(
-            "SELECT msg, sender, recv, timestamp, read FROM msgs 
-            WHERE (sender = ?1 AND recv = ?2) OR (sender = ?2 AND recv = ?1)
-            ORDER BY timestamp DESC LIMIT 1;"
+        let mut msg_stmt = conn.prepare(&format!(
+            "SELECT {MSG_COLUMNS} FROM msgs 
+            WHERE ((sender = ?1 AND recv = ?2) OR (sender = ?2 AND recv = ?1))
+            AND id NOT IN (SELECT msg_id FROM hidden_msgs WHERE username = ?1)
+            ORDER BY id DESC LIMIT 1;"
+        ))?;
+
+        let mut draft_stmt = conn.prepare(
+            "SELECT msg FROM drafts 
+            WHERE username = ?1 AND recv IS ?2 AND room IS ?3;"
         )?;
 
         let conts = response.into_iter().map(|cont| {
             let cont = cont.unwrap();
-            let msg = msg_stmt.query_row( params![user_id, cont], 
-            |row| Ok(WsMessage {
-                msg: row.get(0)?,
-                sender: row.get(1)?,
-                recv: row.get(2)?,
-                time: row.get(3)?,
-                read: row.get(4)?,
-            }));
+            let msg = msg_stmt.query_row(params![user_id, cont], WsMessage::from_row);
+            let draft = draft_stmt.query_row(params![user_id, cont, None::<i64>], |row| row.get(0));
 
             ContactPreview {
                 name: cont,
-                last_msg: msg.ok()
+                last_msg: msg.ok(),
+                room: None,
+                draft: draft.ok(),
+            }
+        }).collect::<Vec<_>>();
+
+        // Group conversations are listed next to the one-to-one chats
+        let mut room_stmt = conn.prepare(
+            "SELECT rooms.id, rooms.name FROM room_members 
+            INNER JOIN rooms ON rooms.id = room_members.room
+            WHERE room_members.username = ?1 AND rooms.name LIKE (?2);"
+        )?;
+
+        let rooms = room_stmt.query_map(
+            params![user_id, search], 
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?))
+        )?;
+
+        let mut room_msg_stmt = conn.prepare(&format!(
+            "SELECT {MSG_COLUMNS} FROM msgs 
+            WHERE room = ?1
+            AND id NOT IN (SELECT msg_id FROM hidden_msgs WHERE username = ?2)
+            ORDER BY id DESC LIMIT 1;"
+        ))?;
+
+        let rooms = rooms.into_iter().map(|room| {
+            let (id, name) = room.unwrap();
+            let msg = room_msg_stmt.query_row(params![id, user_id], WsMessage::from_row);
+            let draft = draft_stmt.query_row(params![user_id, None::<String>, id], |row| row.get(0));
+
+            ContactPreview {
+                name,
+                last_msg: msg.ok(),
+                room: Some(id),
+                draft: draft.ok(),
             }
         });
 
-        Ok(conts.collect())
+        Ok(conts.into_iter().chain(rooms).collect())
     }).await?;
 
     Ok(web::Json(contacts))
@@ -69,6 +110,10 @@ struct Contact {
     name: String,
     last_time: Option<u64>,
     bio: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disappearing: Option<Disappearing>,
+    /// Messages pinned to the top of the chat
+    pins: Vec<Pin>,
 }
 
 #[get("/contact/{username}")]
@@ -87,6 +132,8 @@ pub async fn contact_info(session: Session, db: web::Data<Pool>, username: web::
                 name: row.get(0)?,
                 last_time: row.get(1)?,
                 bio: row.get(2)?,
+                disappearing: Disappearing::load(conn, &user_id, &name, None)?,
+                pins: Pin::list(conn, &user_id, &name, None)?,
             })
         )
     }).await?;
@@ -130,4 +177,41 @@ pub async fn delete_contact(session: Session, db: web::Data<Pool>, username: web
         Ok("Removed from contacts")
     }
 
-}
\ No newline at end of file
+}
+
+#[post("/block/{username}")]
+pub async fn block(session: Session, db: web::Data<Pool>, username: web::Path<String>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    if user_id == username.clone() {
+        return Err(error::ErrorBadRequest("You can't block yourself"));
+    }
+
+    db::execute(&db, move |conn| {
+        conn.execute(
+            "INSERT OR IGNORE INTO blocks (blocker, blocked) 
+            VALUES (?1, ?2)", 
+            params![user_id, username.into_inner()]
+        )
+    }).await?;
+
+    Ok("Blocked")
+}
+
+#[post("/unblock/{username}")]
+pub async fn unblock(session: Session, db: web::Data<Pool>, username: web::Path<String>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    let rows = db::execute(&db, move |conn| {
+        conn.execute(
+            "DELETE FROM blocks WHERE blocker = ?1 AND blocked = ?2", 
+            params![user_id, username.into_inner()]
+        )
+    }).await?;
+
+    if rows == 0 {
+        Err(error::ErrorBadRequest("That user wasn't blocked"))
+    } else {
+        Ok("Unblocked")
+    }
+}
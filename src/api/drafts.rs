@@ -0,0 +1,41 @@
+use actix::Addr;
+use actix_session::Session;
+use actix_web::{error, post, web, Responder};
+use serde::Deserialize;
+
+use crate::{api::auth::validate_session, ws::{ChatServer, SaveDraft}};
+
+#[derive(Debug, Deserialize)]
+struct DraftBody {
+    #[serde(default)]
+    recv: String,
+    #[serde(default)]
+    room: Option<i64>,
+    #[serde(default)]
+    msg: String,
+}
+
+/// Keeps what the user is writing to `recv` or to the room, an empty draft is the same as clearing it
+#[post("/draft")]
+pub async fn save_draft(session: Session, body: web::Json<DraftBody>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let DraftBody { recv, room, msg } = body.into_inner();
+
+    srv.send(SaveDraft { user: user_id, recv, room, msg: Some(msg) })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok("Saved")
+}
+
+#[post("/clear-draft")]
+pub async fn clear_draft(session: Session, body: web::Json<DraftBody>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let DraftBody { recv, room, .. } = body.into_inner();
+
+    srv.send(SaveDraft { user: user_id, recv, room, msg: None })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok("Cleared")
+}
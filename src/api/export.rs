@@ -0,0 +1,277 @@
+use std::{fmt::Write, str::FromStr, time::{SystemTime, UNIX_EPOCH}};
+
+use actix_session::Session;
+use actix_web::{error, get, http::header::{ContentDisposition, DispositionParam, DispositionType}, web::{self, Bytes}, HttpResponse, Responder};
+use futures::stream;
+use rusqlite::{params, Transaction};
+use serde::Deserialize;
+
+use crate::{api::auth::validate_session, date, db::{self, Pool}, ws::{MessageStatus, Poll, WsMessage, MSG_COLUMNS}};
+
+/// Messages read from the database at once, so long conversations are never held whole in memory
+pub const EXPORT_BATCH_SIZE: u32 = 500;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    /// One message per line, as the server sends them
+    #[default]
+    Json,
+    /// A page that can be opened without the server
+    Html,
+    Text,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryExport {
+    #[serde(default)]
+    format: ExportFormat,
+}
+
+impl FromStr for ExportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "json" => Ok(ExportFormat::Json),
+            "html" => Ok(ExportFormat::Html),
+            "text" => Ok(ExportFormat::Text),
+            _ => Err(format!("Unknown format {format}, it has to be json, html or text")),
+        }
+    }
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "application/x-ndjson",
+            ExportFormat::Html => "text/html; charset=utf-8",
+            ExportFormat::Text => "text/plain; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Json => "jsonl",
+            ExportFormat::Html => "html",
+            ExportFormat::Text => "txt",
+        }
+    }
+
+    /// What goes before the messages of the conversation between `user` and `other`
+    pub fn header(&self, user: &str, other: &str) -> String {
+        let now = format_time(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64);
+
+        match self {
+            ExportFormat::Json => String::new(),
+            ExportFormat::Html => format!(
+                "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>{user} and {other}</title>
+<style>
+body {{ font-family: sans-serif; background: #eee; max-width: 48em; margin: auto; padding: 1em; }}
+.msg {{ background: #fff; border-radius: .5em; padding: .5em .75em; margin: .5em 0; max-width: 75%; }}
+.mine {{ background: #dcf8c6; margin-left: auto; }}
+.text {{ white-space: pre-wrap; overflow-wrap: anywhere; }}
+.meta, .quote, .forwarded {{ color: #666; font-size: .8em; }}
+.quote {{ border-left: 3px solid #999; padding-left: .5em; margin-bottom: .25em; }}
+</style>
+</head>
+<body>
+<h1>{user} and {other}</h1>
+<p class=\"meta\">Exported on {now} UTC</p>
+",
+                user = escape(user),
+                other = escape(other),
+            ),
+            ExportFormat::Text => format!("Conversation between {user} and {other}\nExported on {now} UTC\n\n"),
+        }
+    }
+
+    /// A message as seen by `user`, whose messages are told apart in the page
+    pub fn message(&self, msg: &WsMessage, user: &str) -> String {
+        let mut out = String::new();
+        let status = match msg.status {
+            MessageStatus::Sent => "sent",
+            MessageStatus::Delivered => "delivered",
+            MessageStatus::Read => "read",
+        };
+
+        match self {
+            ExportFormat::Json => {
+                // A message always serializes
+                out.push_str(&serde_json::to_string(msg).unwrap_or_default());
+                out.push('\n');
+            }
+            ExportFormat::Html => {
+                let _ = writeln!(out, "<div class=\"msg{}\" id=\"msg-{}\">", if msg.sender == user { " mine" } else { "" }, msg.id.unwrap_or_default());
+                if let Some(forwarded) = &msg.forwarded_from {
+                    let _ = writeln!(out, "<div class=\"forwarded\">Forwarded from {}</div>", escape(forwarded));
+                }
+                if let Some(quote) = &msg.reply_to {
+                    let snippet = if quote.deleted { "Deleted message".to_owned() } else { escape(&quote.snippet) };
+                    let _ = writeln!(out, "<div class=\"quote\"><a href=\"#msg-{}\">{}</a>: {snippet}</div>", quote.id, escape(&quote.sender));
+                }
+                let _ = writeln!(out, "<div><b>{}</b></div>", escape(&msg.sender));
+                let _ = writeln!(out, "<div class=\"text\">{}</div>", escape(&msg.msg));
+                if let Some(poll) = &msg.poll {
+                    out.push_str("<ul>\n");
+                    for (option, votes) in poll_results(poll) {
+                        let _ = writeln!(out, "<li>{} ({votes})</li>", escape(option));
+                    }
+                    out.push_str("</ul>\n");
+                }
+                for attachment in &msg.attachments {
+                    let name = attachment.name.as_deref().unwrap_or("Attachment");
+                    let _ = writeln!(out, "<div class=\"meta\">{} ({}, {} bytes, attachment {})</div>", escape(name), escape(&attachment.mime), attachment.size, attachment.id);
+                }
+                let _ = writeln!(
+                    out,
+                    "<div class=\"meta\"><time datetime=\"{}\">{}</time>{} · {status}</div>",
+                    format_time(msg.time).replace(' ', "T") + "Z",
+                    format_time(msg.time),
+                    if msg.edited_at.is_some() { " · edited" } else { "" }
+                );
+                out.push_str("</div>\n");
+            }
+            ExportFormat::Text => {
+                let _ = write!(out, "[{}, {status}{}] {}", format_time(msg.time), if msg.edited_at.is_some() { ", edited" } else { "" }, msg.sender);
+                if let Some(forwarded) = &msg.forwarded_from {
+                    let _ = write!(out, " (forwarded from {forwarded})");
+                }
+                let _ = writeln!(out, ": {}", msg.msg);
+                if let Some(poll) = &msg.poll {
+                    for (option, votes) in poll_results(poll) {
+                        let _ = writeln!(out, "    - {option} ({votes})");
+                    }
+                }
+                if let Some(quote) = &msg.reply_to {
+                    let snippet = if quote.deleted { "a deleted message" } else { &quote.snippet };
+                    let _ = writeln!(out, "    in reply to {}: {snippet}", quote.sender);
+                }
+                for attachment in &msg.attachments {
+                    let name = attachment.name.as_deref().unwrap_or("attachment");
+                    let _ = writeln!(out, "    {name} ({}, {} bytes, attachment {})", attachment.mime, attachment.size, attachment.id);
+                }
+            }
+        }
+
+        out
+    }
+
+    pub fn footer(&self) -> String {
+        match self {
+            ExportFormat::Html => "</body>\n</html>\n".to_owned(),
+            _ => String::new(),
+        }
+    }
+}
+
+/// The messages between `user` and `other` sent after the message `after`, the oldest first.
+/// Deleted messages are left out, and so are the ones `user` hid if `skip_hidden` is set
+pub fn export_batch(conn: &Transaction, user: &str, other: &str, after: i64, skip_hidden: bool) -> Result<Vec<WsMessage>, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {MSG_COLUMNS} FROM msgs
+        WHERE room IS NULL AND ((sender = ?1 AND recv = ?2) OR (sender = ?2 AND recv = ?1))
+        AND id > ?3 AND deleted = 0
+        AND (?4 = 0 OR id NOT IN (SELECT msg_id FROM hidden_msgs WHERE username = ?1))
+        ORDER BY id
+        LIMIT ?5;"
+    ))?;
+
+    let msgs = stmt.query_map(params![user, other, after, skip_hidden, EXPORT_BATCH_SIZE], WsMessage::from_row)?;
+    msgs.collect()
+}
+
+/// Each option of the poll with how many votes it got, and who voted if anyone did
+fn poll_results(poll: &Poll) -> impl Iterator<Item = (&str, String)> {
+    poll.options.iter().map(|option| {
+        let votes = match option.votes {
+            0 => "no votes".to_owned(),
+            1 => format!("1 vote: {}", option.voters.join(", ")),
+            votes => format!("{votes} votes: {}", option.voters.join(", ")),
+        };
+        (option.text.as_str(), votes)
+    })
+}
+
+/// `YYYY-MM-DD hh:mm:ss` in UTC, from a time in milliseconds
+pub fn format_time(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (days, secs) = (secs / 86_400, secs % 86_400);
+
+    let (year, month, day) = date::civil_from_days(days as i64);
+
+    format!("{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+/// The text with the characters that mean something in HTML written as entities
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Where the export is while it's being streamed
+enum Part {
+    Header,
+    /// The messages after this one
+    Messages(i64),
+    Footer,
+    Done,
+}
+
+/// The whole conversation with `username` as a download, read a batch at a time while it's sent
+#[get("/export/{username}")]
+pub async fn export(session: Session, db: web::Data<Pool>, username: web::Path<String>, query: web::Query<QueryExport>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let username = username.into_inner();
+    let format = query.format;
+
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(format!("{user_id}-{username}.{}", format.extension()))],
+    };
+
+    let body = stream::unfold(Part::Header, move |part| {
+        let (db, user_id, username) = (db.clone(), user_id.clone(), username.clone());
+
+        async move {
+            match part {
+                Part::Header => Some((Ok(Bytes::from(format.header(&user_id, &username))), Part::Messages(0))),
+                Part::Messages(after) => {
+                    let user = user_id.clone();
+                    let msgs = match db::execute(&db, move |conn| export_batch(conn, &user, &username, after, true)).await {
+                        Ok(msgs) => msgs,
+                        Err(err) => return Some((Err(err), Part::Done)),
+                    };
+
+                    let next = match msgs.last().and_then(|msg| msg.id) {
+                        Some(last) if msgs.len() == EXPORT_BATCH_SIZE as usize => Part::Messages(last),
+                        _ => Part::Footer,
+                    };
+                    let chunk: String = msgs.iter().map(|msg| format.message(msg, &user_id)).collect();
+                    Some((Ok(Bytes::from(chunk)), next))
+                }
+                Part::Footer => Some((Ok(Bytes::from(format.footer())), Part::Done)),
+                Part::Done => None,
+            }
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header(disposition)
+        .streaming::<_, error::Error>(body))
+}
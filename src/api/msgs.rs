@@ -2,17 +2,86 @@ use actix::Addr;
 use actix_session::Session;
 use actix_web::{error, get, post, web, Responder};
 
-use rusqlite::params;
+use rusqlite::{params, ToSql, Transaction};
 use serde::{Deserialize, Serialize};
 
-use crate::{api::auth::validate_session, db::{self, Pool}, ws::{ChatServer, ReadMessage, WsMessage}};
+use crate::{api::auth::validate_session, db::{self, Pool}, ws::{policy::can_see, ChatServer, DeleteMessage, Disappearing, EditMessage, ForwardMessages, Pin, PinMessage, React, ReadMessage, SetDisappearing, Vote, WsMessage, MSG_COLUMNS}};
 
-const DEFAULT_MESSAGE_PAGE_SIZE: u32 = 10;
+pub const DEFAULT_MESSAGE_PAGE_SIZE: u32 = 10;
+pub const MAX_MESSAGE_PAGE_SIZE: u32 = 100;
 
-#[derive(Debug, Deserialize)]
-struct QueryMessage {
-    size: Option<u32>,
-    offset: Option<u32>,
+/// Selects a page of a conversation by the messages around it, so it doesn't move when new messages arrive.
+/// The bounds can be message ids or times, and are never included in the page
+#[derive(Debug, Default, Deserialize)]
+pub struct QueryMessage {
+    pub size: Option<u32>,
+    pub before: Option<i64>,
+    pub after: Option<i64>,
+    pub before_time: Option<u64>,
+    pub after_time: Option<u64>,
+}
+
+/// Messages from the newest to the oldest, with the cursors to keep scrolling
+#[derive(Debug, Serialize)]
+pub struct MessagePage {
+    pub msgs: Vec<WsMessage>,
+    /// Pass it as `before` to get the older messages, if there are any
+    pub next: Option<i64>,
+    /// Pass it as `after` to get the newer messages, if there are any
+    pub prev: Option<i64>,
+}
+
+impl QueryMessage {
+    /// Reads the page of the conversation matched by `filter`, as seen by `user`.
+    /// In `filter`, `?1` is the user and `?2` is `target`
+    pub fn page(&self, conn: &Transaction, user: &str, filter: &str, target: &dyn ToSql) -> Result<MessagePage, rusqlite::Error> {
+        let size = self.size.unwrap_or(DEFAULT_MESSAGE_PAGE_SIZE).clamp(1, MAX_MESSAGE_PAGE_SIZE);
+        let bounded_above = self.before.is_some() || self.before_time.is_some();
+        let bounded_below = self.after.is_some() || self.after_time.is_some();
+        // Only the messages right after the bound if there's nothing above
+        let ascending = bounded_below && !bounded_above;
+
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {MSG_COLUMNS} FROM msgs 
+            WHERE {filter}
+            AND id NOT IN (SELECT msg_id FROM hidden_msgs WHERE username = ?1)
+            AND id < ?3 AND id > ?4 AND timestamp < ?5 AND timestamp > ?6
+            ORDER BY id {}
+            LIMIT ?7;",
+            if ascending { "ASC" } else { "DESC" }
+        ))?;
+
+        // One more than asked for, to know if there are more
+        let mut msgs = stmt.query_map(
+            params![
+                user, 
+                target, 
+                self.before.unwrap_or(i64::MAX), 
+                self.after.unwrap_or(0),
+                self.before_time.unwrap_or(i64::MAX as u64),
+                self.after_time.map_or(-1, |time| time as i64),
+                size + 1
+            ],
+            WsMessage::from_row
+        )?.collect::<Result<Vec<_>, _>>()?;
+
+        let more = msgs.len() > size as usize;
+        msgs.truncate(size as usize);
+        if ascending {
+            msgs.reverse();
+        }
+
+        let (older, newer) = match ascending {
+            true => (bounded_below, more),
+            false => (more || bounded_below, bounded_above),
+        };
+
+        Ok(MessagePage {
+            next: msgs.last().and_then(|msg| msg.id).filter(|_| older),
+            prev: msgs.first().and_then(|msg| msg.id).filter(|_| newer),
+            msgs,
+        })
+    }
 }
 
 #[get("/msgs/{username}")]
@@ -21,80 +90,421 @@ pub async fn get_messages(session: Session, db: web::Data<Pool>, username: web::
     let username = username.into_inner();
     let query = query.into_inner();
 
-    let msgs: Vec<WsMessage> = db::execute(&db, move |conn| {
-        let mut stmt = conn.prepare(
-            "SELECT msg, sender, recv, timestamp, read FROM msgs 
-            WHERE (sender = ?1 AND recv = ?2) OR (sender = ?2 AND recv = ?1)
-            ORDER BY timestamp DESC
-            LIMIT ?3 OFFSET ?4;"
-        )?;
-
-        let response = stmt.query_map(
-            params![user_id, username, query.size.unwrap_or(DEFAULT_MESSAGE_PAGE_SIZE), query.offset.unwrap_or(0)], 
-            |row| Ok(WsMessage {
-                msg: row.get(0)?,
-                sender: row.get(1)?,
-                recv: row.get(2)?,
-                time: row.get(3)?,
-                read: row.get(4)?,
-            })
-        )?;
-
-        response.into_iter().collect()
+    let page = db::execute(&db, move |conn| {
+        query.page(conn, &user_id, "((sender = ?1 AND recv = ?2) OR (sender = ?2 AND recv = ?1))", &username)
     }).await?;
 
-    Ok(web::Json(msgs))
+    Ok(web::Json(page))
 }
 
 #[derive(Debug, Default, Serialize)]
 struct UnreadResponse {
     contact: String,
-    unread: u32
+    unread: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room: Option<i64>,
 }
 
 #[get("/unread")]
 pub async fn get_unread(session: Session, db: web::Data<Pool>) -> Result<impl Responder, error::Error> {
     let user_id = validate_session(&session)?;
 
-    let unread: Vec<UnreadResponse> = db::execute(&db, move |conn| {
+    let unread = db::execute(&db, move |conn| unread_counts(conn, &user_id)).await?;
+
+    Ok(web::Json(unread))
+}
+
+/// Unread messages of each one-to-one chat and room of the user. Notices are never counted
+fn unread_counts(conn: &Transaction, user_id: &str) -> Result<Vec<UnreadResponse>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT sender, COUNT(sender) FROM msgs 
+        WHERE read_at IS NULL AND recv = ?1
+        GROUP BY sender;"
+    )?;
+
+    let response = stmt.query_map(
+        params![user_id], 
+        |row| Ok(UnreadResponse {
+            contact: row.get(0)?,
+            unread: row.get(1)?,
+            room: None,
+        })
+    )?;
+
+    let mut room_stmt = conn.prepare(
+        "SELECT rooms.name, COUNT(msgs.id), rooms.id FROM room_members 
+        INNER JOIN rooms ON rooms.id = room_members.room
+        INNER JOIN msgs ON msgs.room = rooms.id AND msgs.id > room_members.last_read AND msgs.sender != ?1
+        WHERE room_members.username = ?1 AND (msgs.kind IS NULL OR msgs.kind ->> '$.type' != 'system')
+        GROUP BY rooms.id;"
+    )?;
+
+    let rooms = room_stmt.query_map(
+        params![user_id], 
+        |row| Ok(UnreadResponse {
+            contact: row.get(0)?,
+            unread: row.get(1)?,
+            room: row.get(2)?,
+        })
+    )?;
+
+    response.into_iter().chain(rooms).collect()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryRead {
+    pub up_to: Option<i64>,
+}
+
+#[post("/read/{username}")]
+pub async fn read(session: Session, username: web::Path<String>, query: web::Query<QueryRead>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    
+    srv.send(ReadMessage { 
+        reader: user_id, 
+        writer: username.into_inner(),
+        up_to: query.up_to 
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+    
+    Ok("Read")
+}
+
+#[derive(Debug, Deserialize)]
+struct EditBody {
+    msg: String
+}
+
+#[post("/edit-message/{id}")]
+pub async fn edit_message(session: Session, id: web::Path<i64>, body: web::Json<EditBody>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    let msg = srv.send(EditMessage { 
+        editor: user_id, 
+        id: id.into_inner(), 
+        msg: body.into_inner().msg,
+        origin: None,
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+
+    Ok(web::Json(msg))
+}
+
+#[derive(Debug, Deserialize)]
+struct QueryDelete {
+    everyone: Option<bool>,
+}
+
+#[post("/delete-message/{id}")]
+pub async fn delete_message(session: Session, id: web::Path<i64>, query: web::Query<QueryDelete>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    srv.send(DeleteMessage { 
+        user: user_id, 
+        id: id.into_inner(), 
+        everyone: query.everyone.unwrap_or(false),
+        origin: None,
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+
+    Ok("Deleted")
+}
+
+#[derive(Debug, Deserialize)]
+struct ReactionBody {
+    emoji: String
+}
+
+#[post("/react/{id}")]
+pub async fn react(session: Session, id: web::Path<i64>, body: web::Json<ReactionBody>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    let (_, reactions) = srv.send(React { 
+        user: user_id, 
+        id: id.into_inner(), 
+        emoji: body.into_inner().emoji, 
+        add: true,
+        origin: None,
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+
+    Ok(web::Json(reactions))
+}
+
+#[post("/unreact/{id}")]
+pub async fn unreact(session: Session, id: web::Path<i64>, body: web::Json<ReactionBody>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    let (_, reactions) = srv.send(React { 
+        user: user_id, 
+        id: id.into_inner(), 
+        emoji: body.into_inner().emoji, 
+        add: false,
+        origin: None,
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+
+    Ok(web::Json(reactions))
+}
+
+#[derive(Debug, Deserialize)]
+struct VoteBody {
+    options: Vec<usize>,
+}
+
+/// Replaces the votes of the user in a poll, with no options to take them back
+#[post("/vote/{id}")]
+pub async fn vote(session: Session, id: web::Path<i64>, body: web::Json<VoteBody>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    let (_, poll) = srv.send(Vote { 
+        user: user_id, 
+        id: id.into_inner(), 
+        options: body.into_inner().options,
+        origin: None,
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+
+    Ok(web::Json(poll))
+}
+
+#[derive(Debug, Deserialize)]
+struct ForwardBody {
+    ids: Vec<i64>,
+    #[serde(default)]
+    recv: String,
+    #[serde(default)]
+    room: Option<i64>,
+}
+
+/// Copies the messages to the chat with `recv` or to the room, answered with the copies
+#[post("/forward")]
+pub async fn forward(session: Session, body: web::Json<ForwardBody>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let ForwardBody { ids, recv, room } = body.into_inner();
+
+    let msgs = srv.send(ForwardMessages { user: user_id, ids, recv, room })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok(web::Json(msgs))
+}
+
+#[post("/pin/{id}")]
+pub async fn pin(session: Session, id: web::Path<i64>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    let pin = srv.send(PinMessage { 
+        user: user_id, 
+        id: id.into_inner(), 
+        pin: true,
+        origin: None,
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+
+    Ok(web::Json(pin))
+}
+
+#[post("/unpin/{id}")]
+pub async fn unpin(session: Session, id: web::Path<i64>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    srv.send(PinMessage { 
+        user: user_id, 
+        id: id.into_inner(), 
+        pin: false,
+        origin: None,
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+
+    Ok("Unpinned")
+}
+
+#[get("/pins/{username}")]
+pub async fn get_pins(session: Session, db: web::Data<Pool>, username: web::Path<String>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let username = username.into_inner();
+
+    let pins = db::execute(&db, move |conn| Pin::list(conn, &user_id, &username, None)).await?;
+
+    Ok(web::Json(pins))
+}
+
+/// Sets how long the new messages in the chat with `username` last, or makes them last forever with `null`
+#[post("/disappearing/{username}")]
+pub async fn set_disappearing(session: Session, username: web::Path<String>, body: web::Json<Option<Disappearing>>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    let disappearing = srv.send(SetDisappearing { 
+        user: user_id, 
+        recv: username.into_inner(), 
+        room: None, 
+        disappearing: body.into_inner() 
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+
+    Ok(web::Json(disappearing))
+}
+
+#[derive(Debug, Serialize)]
+struct Edit {
+    msg: String,
+    edited_at: u64,
+}
+
+#[get("/edit-history/{id}")]
+pub async fn edit_history(session: Session, db: web::Data<Pool>, id: web::Path<i64>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let id = id.into_inner();
+
+    let edits: Option<Vec<Edit>> = db::execute(&db, move |conn| {
+        match WsMessage::load(conn, id)? {
+            Some(msg) if can_see(conn, &user_id, &msg)? => (),
+            _ => return Ok(None),
+        }
+
         let mut stmt = conn.prepare(
-            "SELECT sender, COUNT(sender) FROM msgs 
-            WHERE read = 0 AND recv = ?1
-            GROUP BY sender;"
+            "SELECT msg, edited_at FROM msg_edits 
+            WHERE msg_id = ?1
+            ORDER BY edited_at;"
         )?;
 
         let response = stmt.query_map(
-            params![user_id], 
-            |row| Ok(UnreadResponse {
-                contact: row.get(0)?,
-                unread: row.get(1)?
+            params![id], 
+            |row| Ok(Edit {
+                msg: row.get(0)?,
+                edited_at: row.get(1)?,
             })
         )?;
 
-        response.into_iter().collect()
+        response.into_iter().collect::<Result<_, _>>().map(Some)
     }).await?;
 
-    Ok(web::Json(unread))
+    edits.map(web::Json)
+        .ok_or_else(|| error::ErrorNotFound("That message doesn't exist"))
 }
 
-#[post("/read/{username}")]
-pub async fn read(session: Session, db: web::Data<Pool>, username: web::Path<String>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
-    let user_id = validate_session(&session)?;
-    let username = username.into_inner();
-    
-    let msg = ReadMessage { 
-        reader: user_id.clone(), 
-        writer: username.clone() 
-    };
-    
-    db::execute(&db, move |conn| {
-        conn.execute(
-            "UPDATE msgs SET read = 1 WHERE recv = ?1 AND sender = ?2;", 
-            params![user_id, username]
-        )
-    }).await?;
-    
-    srv.do_send(msg);
-    
-    Ok("Read")
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILTER: &str = "((sender = ?1 AND recv = ?2) OR (sender = ?2 AND recv = ?1))";
+
+    /// A conversation of `count` messages, one second apart, with ids from 1
+    fn conversation(count: i64) -> rusqlite::Connection {
+        let conn = db::open_in_memory();
+        conn.execute_batch("INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', ''), ('carol', '');").unwrap();
+        for i in 1..=count {
+            let (sender, recv) = if i % 2 == 0 { ("alice", "bob") } else { ("bob", "alice") };
+            conn.execute(
+                "INSERT INTO msgs (sender, recv, msg, timestamp) VALUES (?1, ?2, ?3, ?4);",
+                params![sender, recv, format!("message {i}"), i * 1000],
+            ).unwrap();
+        }
+        // Newer than all of them, but in another conversation
+        conn.execute("INSERT INTO msgs (sender, recv, msg, timestamp) VALUES ('carol', 'alice', 'hi', 5500);", []).unwrap();
+        conn
+    }
+
+    fn page(conn: &mut rusqlite::Connection, query: QueryMessage) -> (Vec<i64>, Option<i64>, Option<i64>) {
+        let tx = conn.transaction().unwrap();
+        let page = query.page(&tx, "alice", FILTER, &"bob").unwrap();
+        (page.msgs.iter().filter_map(|msg| msg.id).collect(), page.next, page.prev)
+    }
+
+    #[test]
+    fn newest_page() {
+        let mut conn = conversation(25);
+        let (ids, next, prev) = page(&mut conn, QueryMessage::default());
+
+        assert_eq!(ids, (16..=25).rev().collect::<Vec<_>>());
+        assert_eq!(next, Some(16));
+        assert_eq!(prev, None);
+    }
+
+    #[test]
+    fn scrolls_back_to_the_oldest() {
+        let mut conn = conversation(25);
+        let (ids, next, prev) = page(&mut conn, QueryMessage { before: Some(16), ..Default::default() });
+        assert_eq!(ids, (6..=15).rev().collect::<Vec<_>>());
+        assert_eq!((next, prev), (Some(6), Some(15)));
+
+        let (ids, next, prev) = page(&mut conn, QueryMessage { before: Some(6), ..Default::default() });
+        assert_eq!(ids, vec![5, 4, 3, 2, 1]);
+        assert_eq!((next, prev), (None, Some(5)));
+    }
+
+    #[test]
+    fn after_takes_the_messages_right_after() {
+        let mut conn = conversation(25);
+        let (ids, next, prev) = page(&mut conn, QueryMessage { after: Some(3), size: Some(5), ..Default::default() });
+        assert_eq!(ids, vec![8, 7, 6, 5, 4]);
+        assert_eq!((next, prev), (Some(4), Some(8)));
+
+        let (ids, next, prev) = page(&mut conn, QueryMessage { after: Some(20), ..Default::default() });
+        assert_eq!(ids, (21..=25).rev().collect::<Vec<_>>());
+        assert_eq!((next, prev), (Some(21), None));
+    }
+
+    #[test]
+    fn between_two_bounds() {
+        let mut conn = conversation(25);
+        let (ids, next, prev) = page(&mut conn, QueryMessage { before: Some(10), after: Some(6), ..Default::default() });
+        assert_eq!(ids, vec![9, 8, 7]);
+        assert_eq!((next, prev), (Some(7), Some(9)));
+    }
+
+    #[test]
+    fn bounded_by_time() {
+        let mut conn = conversation(25);
+        let (ids, _, _) = page(&mut conn, QueryMessage { before_time: Some(4000), ..Default::default() });
+        assert_eq!(ids, vec![3, 2, 1]);
+
+        let (ids, _, _) = page(&mut conn, QueryMessage { after_time: Some(22000), ..Default::default() });
+        assert_eq!(ids, vec![25, 24, 23]);
+
+        let (ids, _, _) = page(&mut conn, QueryMessage { after_time: Some(0), size: Some(2), ..Default::default() });
+        assert_eq!(ids, vec![2, 1]);
+    }
+
+    #[test]
+    fn skips_hidden_messages() {
+        let mut conn = conversation(5);
+        conn.execute("INSERT INTO hidden_msgs (username, msg_id) VALUES ('alice', 4);", []).unwrap();
+
+        let (ids, _, _) = page(&mut conn, QueryMessage::default());
+        assert_eq!(ids, vec![5, 3, 2, 1]);
+    }
+
+    #[test]
+    fn notices_are_never_unread() {
+        let mut conn = conversation(0);
+        let tx = conn.transaction().unwrap();
+        tx.execute_batch(
+            "INSERT INTO rooms (name, owner) VALUES ('r', 'bob');
+            INSERT INTO room_members (room, username) VALUES (1, 'alice'), (1, 'bob');
+            INSERT INTO msgs (sender, msg, timestamp, room, kind) VALUES ('bob', 'bob added carol', 0, 1, '{\"type\":\"system\",\"notice\":\"member_added\",\"user\":\"carol\"}');
+            INSERT INTO msgs (sender, msg, timestamp, room) VALUES ('bob', 'hi', 0, 1);"
+        ).unwrap();
+
+        let unread = unread_counts(&tx, "alice").unwrap();
+        assert_eq!(unread.iter().map(|unread| (unread.room, unread.unread)).collect::<Vec<_>>(), vec![(None, 1), (Some(1), 1)]);
+    }
+
+    #[test]
+    fn size_is_clamped() {
+        let mut conn = conversation(3);
+        let (ids, next, _) = page(&mut conn, QueryMessage { size: Some(0), ..Default::default() });
+        assert_eq!(ids, vec![3]);
+        assert_eq!(next, Some(3));
+    }
+}
@@ -0,0 +1,249 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix::Addr;
+use actix_session::Session;
+use actix_web::{error, get, post, web, Responder};
+use rusqlite::{params, OptionalExtension, Transaction};
+use serde::{Deserialize, Serialize};
+
+use crate::{api::{auth::validate_session, msgs::{MessagePage, QueryMessage, QueryRead}}, db::{self, Pool}, ws::{policy::{is_member, room_members}, ChatServer, Disappearing, MessagingPolicy, Notice, Pin, PostNotice, ReadRoom, SetDisappearing}};
+
+#[derive(Debug, Deserialize)]
+struct NewRoom {
+    name: String,
+    members: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct Room {
+    id: i64,
+    name: String,
+    owner: String,
+    members: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    disappearing: Option<Disappearing>,
+    pins: Vec<Pin>,
+}
+
+fn room_owner(conn: &Transaction, room: i64) -> Result<Option<String>, rusqlite::Error> {
+    conn.query_row(
+        "SELECT owner FROM rooms WHERE id = ?1",
+        params![room],
+        |row| row.get(0)
+    )
+    .optional()
+}
+
+#[post("/create-room")]
+pub async fn create_room(session: Session, db: web::Data<Pool>, policy: web::Data<MessagingPolicy>, input: web::Json<NewRoom>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let input = input.into_inner();
+
+    if input.name.trim().is_empty() {
+        return Err(error::ErrorBadRequest("The room needs a name"));
+    }
+
+    let policy = policy.get_ref().clone();
+    let room = db::execute_immediate(&db, move |conn| {
+        // Every member is checked before the room is made, so nobody can reach who they couldn't message
+        let mut members = vec![user_id.clone()];
+        for member in input.members {
+            if members.contains(&member) {
+                continue;
+            }
+            if let Err(err) = policy.check_member(conn, &user_id, &members, &member)? {
+                return Ok(Err(err));
+            }
+            members.push(member);
+        }
+
+        let id: i64 = conn.query_row(
+            "INSERT INTO rooms (name, owner, created) VALUES (?1, ?2, ?3) RETURNING (id)",
+            params![
+                input.name,
+                user_id,
+                SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+            ],
+            |row| row.get(0)
+        )?;
+
+        let mut stmt = conn.prepare("INSERT INTO room_members (room, username) VALUES (?1, ?2)")?;
+        for member in &members {
+            stmt.execute(params![id, member])?;
+        }
+
+        let members = room_members(conn, id)?;
+
+        Ok(Ok(Room { id, name: input.name, owner: user_id, members, disappearing: None, pins: Vec::new() }))
+    }).await??;
+
+    srv.send(PostNotice { sender: room.owner.clone(), room: Some(room.id), notice: Notice::RoomCreated { name: room.name.clone() } })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok(web::Json(room))
+}
+
+#[get("/room/{id}")]
+pub async fn room_info(session: Session, db: web::Data<Pool>, id: web::Path<i64>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let id = id.into_inner();
+
+    let room = db::execute(&db, move |conn| {
+        if !is_member(conn, id, &user_id)? {
+            return Ok(None);
+        }
+
+        let (name, owner) = conn.query_row(
+            "SELECT name, owner FROM rooms WHERE id = ?1",
+            params![id],
+            |row| Ok((row.get(0)?, row.get(1)?))
+        )?;
+
+        let members = room_members(conn, id)?;
+        let disappearing = Disappearing::load(conn, &user_id, "", Some(id))?;
+        let pins = Pin::list(conn, &user_id, "", Some(id))?;
+
+        Ok(Some(Room { id, name, owner, members, disappearing, pins }))
+    }).await?;
+
+    room.map(web::Json)
+        .ok_or_else(|| error::ErrorForbidden("You aren't a member of this room"))
+}
+
+#[post("/room/{id}/add-member/{username}")]
+pub async fn add_member(session: Session, db: web::Data<Pool>, policy: web::Data<MessagingPolicy>, path: web::Path<(i64, String)>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let (id, username) = path.into_inner();
+
+    let (sender, user) = (user_id.clone(), username.clone());
+    let policy = policy.get_ref().clone();
+    let rows = db::execute_immediate(&db, move |conn| {
+        if room_owner(conn, id)?.as_ref() != Some(&user_id) {
+            return Ok(None);
+        }
+
+        let members = room_members(conn, id)?;
+        if members.contains(&username) {
+            return Ok(Some(Ok(0)));
+        }
+        if let Err(err) = policy.check_member(conn, &user_id, &members, &username)? {
+            return Ok(Some(Err(err)));
+        }
+
+        conn.execute(
+            "INSERT INTO room_members (room, username) VALUES (?1, ?2)",
+            params![id, username]
+        ).map(|rows| Some(Ok(rows)))
+    }).await?;
+
+    match rows {
+        None => return Err(error::ErrorForbidden("Only the owner can add members")),
+        Some(Err(err)) => return Err(err.into()),
+        Some(Ok(0)) => return Err(error::ErrorBadRequest("It wasn't added")),
+        Some(Ok(_)) => (),
+    }
+
+    srv.send(PostNotice { sender, room: Some(id), notice: Notice::MemberAdded { user } })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok("Added to the room")
+}
+
+#[post("/room/{id}/remove-member/{username}")]
+pub async fn remove_member(session: Session, db: web::Data<Pool>, path: web::Path<(i64, String)>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let (id, username) = path.into_inner();
+
+    let (sender, user) = (user_id.clone(), username.clone());
+    let rows = db::execute_immediate(&db, move |conn| {
+        // Anyone can leave a room, but only the owner can kick other members
+        if user_id != username && room_owner(conn, id)?.as_ref() != Some(&user_id) {
+            return Ok(None);
+        }
+
+        conn.execute(
+            "DELETE FROM room_members WHERE room = ?1 AND username = ?2",
+            params![id, username]
+        ).map(Some)
+    }).await?;
+
+    match rows {
+        None => return Err(error::ErrorForbidden("Only the owner can remove other members")),
+        Some(0) => return Err(error::ErrorBadRequest("It wasn't removed")),
+        Some(_) => (),
+    }
+
+    srv.send(PostNotice { sender, room: Some(id), notice: Notice::MemberRemoved { user } })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
+
+    Ok("Removed from the room")
+}
+
+#[get("/room/{id}/msgs")]
+pub async fn get_room_messages(session: Session, db: web::Data<Pool>, id: web::Path<i64>, query: web::Query<QueryMessage>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let id = id.into_inner();
+    let query = query.into_inner();
+
+    let page: Option<MessagePage> = db::execute(&db, move |conn| {
+        if !is_member(conn, id, &user_id)? {
+            return Ok(None);
+        }
+
+        query.page(conn, &user_id, "room = ?2", &id).map(Some)
+    }).await?;
+
+    page.map(web::Json)
+        .ok_or_else(|| error::ErrorForbidden("You aren't a member of this room"))
+}
+
+#[get("/room/{id}/pins")]
+pub async fn get_room_pins(session: Session, db: web::Data<Pool>, id: web::Path<i64>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let id = id.into_inner();
+
+    let pins: Option<Vec<Pin>> = db::execute(&db, move |conn| {
+        if !is_member(conn, id, &user_id)? {
+            return Ok(None);
+        }
+
+        Pin::list(conn, &user_id, "", Some(id)).map(Some)
+    }).await?;
+
+    pins.map(web::Json)
+        .ok_or_else(|| error::ErrorForbidden("You aren't a member of this room"))
+}
+
+#[post("/room/{id}/read")]
+pub async fn read_room(session: Session, id: web::Path<i64>, query: web::Query<QueryRead>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    srv.send(ReadRoom { 
+        reader: user_id, 
+        room: id.into_inner(), 
+        up_to: query.up_to 
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+
+    Ok("Read")
+}
+
+#[post("/room/{id}/disappearing")]
+pub async fn set_room_disappearing(session: Session, id: web::Path<i64>, body: web::Json<Option<Disappearing>>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    let disappearing = srv.send(SetDisappearing { 
+        user: user_id, 
+        recv: String::new(), 
+        room: Some(id.into_inner()), 
+        disappearing: body.into_inner() 
+    })
+    .await
+    .map_err(error::ErrorInternalServerError)??;
+
+    Ok(web::Json(disappearing))
+}
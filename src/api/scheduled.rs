@@ -0,0 +1,165 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use actix_session::Session;
+use actix_web::{error, get, post, web, Responder};
+use rusqlite::{params, OptionalExtension, Transaction};
+use serde::Deserialize;
+
+use crate::{api::auth::validate_session, db::{self, Pool}, ws::{check_message, ChatError, MessagingPolicy, NewMessage, ScheduledMessage, SCHEDULED_COLUMNS}};
+
+/// How far in the future a message can be scheduled
+const MAX_SCHEDULE_AHEAD: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+/// Messages each user can have waiting to be sent
+const MAX_SCHEDULED: u32 = 100;
+
+#[derive(Debug, Deserialize)]
+struct NewScheduled {
+    #[serde(flatten)]
+    msg: NewMessage,
+    /// When it has to be sent, in milliseconds
+    send_at: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct EditScheduled {
+    msg: Option<String>,
+    send_at: Option<u64>,
+}
+
+fn check_send_at(send_at: u64) -> Result<(), error::Error> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    if send_at <= now.as_millis() as u64 {
+        return Err(error::ErrorBadRequest("Messages can only be scheduled for the future"));
+    }
+    if send_at > (now + MAX_SCHEDULE_AHEAD).as_millis() as u64 {
+        return Err(error::ErrorBadRequest("That's too far in the future"));
+    }
+
+    Ok(())
+}
+
+fn load_scheduled(conn: &Transaction, id: i64, sender: &str) -> Result<Option<ScheduledMessage>, rusqlite::Error> {
+    conn.query_row(
+        &format!("SELECT {SCHEDULED_COLUMNS} FROM scheduled_msgs WHERE id = ?1 AND sender = ?2"),
+        params![id, sender],
+        ScheduledMessage::from_row
+    )
+    .optional()
+}
+
+/// Messages of the user waiting to be sent, the first to be sent first
+#[get("/scheduled")]
+pub async fn get_scheduled(session: Session, db: web::Data<Pool>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+
+    let scheduled: Vec<ScheduledMessage> = db::execute(&db, move |conn| {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {SCHEDULED_COLUMNS} FROM scheduled_msgs
+            WHERE sender = ?1
+            ORDER BY send_at, id"
+        ))?;
+
+        let scheduled = stmt.query_map(params![user_id], ScheduledMessage::from_row)?;
+        scheduled.collect()
+    }).await?;
+
+    Ok(web::Json(scheduled))
+}
+
+/// The message is checked now, and again when it's sent
+#[post("/schedule")]
+pub async fn schedule(session: Session, db: web::Data<Pool>, policy: web::Data<MessagingPolicy>, body: web::Json<NewScheduled>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let NewScheduled { msg, send_at } = body.into_inner();
+    check_send_at(send_at)?;
+    if msg.poll.is_some() || !msg.kind.is_text() {
+        return Err(error::ErrorBadRequest("Only text messages can be scheduled"));
+    }
+
+    let policy = policy.get_ref().clone();
+    let scheduled = db::execute_immediate(&db, move |conn| {
+        // Counted in the same transaction as the insert, so two requests at once can't both get the last place
+        let pending: u32 = conn.query_row(
+            "SELECT COUNT(*) FROM scheduled_msgs WHERE sender = ?1",
+            params![user_id],
+            |row| row.get(0)
+        )?;
+        if pending >= MAX_SCHEDULED {
+            return Ok(Err(ChatError::TooManyScheduled));
+        }
+
+        if let Err(err) = check_message(conn, &policy, &user_id, &msg)? {
+            return Ok(Err(err));
+        }
+
+        let attachments = serde_json::to_string(&msg.attachments).map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+        let id = conn.query_row(
+            "INSERT INTO scheduled_msgs (msg, sender, recv, room, reply_to, attachments, send_at, created)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8) RETURNING (id)",
+            params![
+                msg.msg,
+                user_id,
+                msg.room.is_none().then_some(&msg.recv),
+                msg.room,
+                msg.reply_to,
+                attachments,
+                send_at,
+                SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64
+            ],
+            |row| row.get(0)
+        )?;
+
+        load_scheduled(conn, id, &user_id)?
+            .ok_or(rusqlite::Error::QueryReturnedNoRows)
+            .map(Ok)
+    }).await??;
+
+    Ok(web::Json(scheduled))
+}
+
+/// Changes the text or the time of a message that hasn't been sent yet.
+/// If it couldn't be sent, it's tried again
+#[post("/edit-scheduled/{id}")]
+pub async fn edit_scheduled(session: Session, db: web::Data<Pool>, id: web::Path<i64>, body: web::Json<EditScheduled>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let id = id.into_inner();
+    let EditScheduled { msg, send_at } = body.into_inner();
+    if let Some(send_at) = send_at {
+        check_send_at(send_at)?;
+    }
+
+    let scheduled = db::execute(&db, move |conn| {
+        let updated = conn.execute(
+            "UPDATE scheduled_msgs SET msg = COALESCE(?1, msg), send_at = COALESCE(?2, send_at), error = NULL
+            WHERE id = ?3 AND sender = ?4",
+            params![msg, send_at, id, user_id]
+        )?;
+        if updated == 0 {
+            return Ok(None);
+        }
+
+        load_scheduled(conn, id, &user_id)
+    }).await?;
+
+    scheduled.map(web::Json)
+        .ok_or_else(|| error::ErrorNotFound("That scheduled message doesn't exist"))
+}
+
+#[post("/cancel-scheduled/{id}")]
+pub async fn cancel_scheduled(session: Session, db: web::Data<Pool>, id: web::Path<i64>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let id = id.into_inner();
+
+    let cancelled = db::execute(&db, move |conn| {
+        conn.execute(
+            "DELETE FROM scheduled_msgs WHERE id = ?1 AND sender = ?2",
+            params![id, user_id]
+        )
+    }).await?;
+
+    if cancelled == 0 {
+        return Err(error::ErrorNotFound("That scheduled message doesn't exist"));
+    }
+
+    Ok("Cancelled")
+}
@@ -0,0 +1,235 @@
+use std::{collections::hash_map::RandomState, hash::{BuildHasher, Hasher}};
+
+use actix_session::Session;
+use actix_web::{error, get, web, Responder};
+use rusqlite::params;
+use serde::{Deserialize, Serialize};
+
+use crate::{api::{auth::validate_session, export::escape, msgs::{DEFAULT_MESSAGE_PAGE_SIZE, MAX_MESSAGE_PAGE_SIZE}}, date, db::{self, Pool}, ws::{WsMessage, MSG_COLUMNS, MSG_COLUMN_COUNT}};
+
+const SNIPPET_TOKENS: u32 = 12;
+
+#[derive(Debug, Deserialize)]
+pub struct QuerySearch {
+    q: String,
+    size: Option<u32>,
+    /// Only the matches older than this message, to get the next page
+    before: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchResult {
+    #[serde(flatten)]
+    msg: WsMessage,
+    /// The part of the message that matched as HTML, escaped, with the words found between `<mark>` and `</mark>`
+    snippet: String,
+}
+
+#[derive(Debug, Serialize)]
+struct SearchPage {
+    results: Vec<SearchResult>,
+    /// Pass it as `before` to get the older matches, if there are any
+    next: Option<i64>,
+}
+
+/// A search as typed by the user, like `from:alice after:2024-01-31 party`
+#[derive(Debug, Default)]
+struct SearchTerms {
+    text: Vec<String>,
+    from: Option<String>,
+    with: Option<String>,
+    before: Option<u64>,
+    after: Option<u64>,
+}
+
+impl SearchTerms {
+    fn parse(query: &str) -> Result<Self, String> {
+        let mut terms = SearchTerms::default();
+
+        for word in query.split_whitespace() {
+            match word.split_once(':') {
+                Some(("from", user)) if !user.is_empty() => terms.from = Some(user.to_string()),
+                Some(("with", user)) if !user.is_empty() => terms.with = Some(user.to_string()),
+                Some(("before", time)) => terms.before = Some(parse_time(time)?),
+                Some(("after", time)) => terms.after = Some(parse_time(time)?),
+                _ => terms.text.push(word.to_string()),
+            }
+        }
+
+        Ok(terms)
+    }
+
+    /// Every word has to be in the message. They are quoted so nothing the user types is taken as FTS5 syntax
+    fn fts_query(&self) -> String {
+        self.text.iter()
+            .map(|word| format!("\"{}\"", word.replace('"', "\"\"")))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Times are a `YYYY-MM-DD` date in UTC, or a time since the epoch with its unit, like `1706659200s` or `1706659200000ms`
+fn parse_time(time: &str) -> Result<u64, String> {
+    let invalid = || format!("{time} isn't a valid date, it should be like 2024-01-31");
+
+    if let Some(millis) = time.strip_suffix("ms") {
+        return millis.parse().map_err(|_| invalid());
+    }
+    if let Some(secs) = time.strip_suffix('s') {
+        return secs.parse::<u64>().ok().and_then(|secs| secs.checked_mul(1000)).ok_or_else(invalid);
+    }
+
+    let parts: Vec<&str> = time.split('-').collect();
+    let [year, month, day] = parts[..] else {
+        return Err(invalid());
+    };
+    if year.len() != 4 || month.len() != 2 || day.len() != 2 {
+        return Err(invalid());
+    }
+    let (Ok(year), Ok(month), Ok(day)) = (year.parse(), month.parse(), day.parse()) else {
+        return Err(invalid());
+    };
+
+    date::to_millis(year, month, day).ok_or_else(invalid)
+}
+
+/// Marks put around the words found in the snippet. They can't be guessed, so no message can have them
+fn snippet_marks() -> (String, String) {
+    let nonce = RandomState::new().build_hasher().finish();
+    (format!("\u{2}{nonce:016x}\u{3}"), format!("\u{3}{nonce:016x}\u{2}"))
+}
+
+/// The snippet as HTML, with the words between the marks highlighted and everything else escaped
+fn highlight(snippet: &str, (open, close): &(String, String)) -> String {
+    let mut html = String::with_capacity(snippet.len());
+
+    for (i, part) in snippet.split(open.as_str()).enumerate() {
+        match part.split_once(close.as_str()) {
+            Some((found, rest)) if i > 0 => {
+                html.push_str("<mark>");
+                html.push_str(&escape(found));
+                html.push_str("</mark>");
+                html.push_str(&escape(rest));
+            }
+            _ => html.push_str(&escape(part)),
+        }
+    }
+
+    html
+}
+
+#[get("/search")]
+pub async fn search(session: Session, db: web::Data<Pool>, query: web::Query<QuerySearch>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let query = query.into_inner();
+
+    let terms = SearchTerms::parse(&query.q).map_err(error::ErrorBadRequest)?;
+    if terms.text.is_empty() {
+        return Err(error::ErrorBadRequest("There's nothing to search for"));
+    }
+    let size = query.size.unwrap_or(DEFAULT_MESSAGE_PAGE_SIZE).clamp(1, MAX_MESSAGE_PAGE_SIZE);
+
+    let marks = snippet_marks();
+
+    let page = db::execute(&db, move |conn| {
+        // Only the messages the user sent or received, in their chats or in the rooms they are in
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {MSG_COLUMNS}, snippet(msgs_fts, 0, ?9, ?10, '…', {SNIPPET_TOKENS}) FROM msgs_fts
+            INNER JOIN msgs ON msgs.id = msgs_fts.rowid
+            WHERE msgs_fts MATCH ?1
+            AND (msgs.sender = ?2 OR msgs.recv = ?2 OR msgs.room IN (SELECT room FROM room_members WHERE username = ?2))
+            AND msgs.deleted = 0
+            AND msgs.id NOT IN (SELECT msg_id FROM hidden_msgs WHERE username = ?2)
+            AND (?3 IS NULL OR msgs.sender = ?3)
+            AND (?4 IS NULL OR (msgs.sender = ?2 AND msgs.recv = ?4) OR (msgs.sender = ?4 AND msgs.recv = ?2))
+            AND msgs.timestamp < ?5 AND msgs.timestamp > ?6
+            AND msgs.id < ?7
+            ORDER BY msgs.id DESC
+            LIMIT ?8;"
+        ))?;
+
+        // One more than asked for, to know if there are more
+        let mut results = stmt.query_map(
+            params![
+                terms.fts_query(),
+                user_id,
+                terms.from,
+                terms.with,
+                terms.before.unwrap_or(i64::MAX as u64),
+                terms.after.map_or(-1, |time| time as i64),
+                query.before.unwrap_or(i64::MAX),
+                size + 1,
+                marks.0,
+                marks.1
+            ],
+            |row| Ok(SearchResult {
+                msg: WsMessage::from_row(row)?,
+                snippet: highlight(&row.get::<_, String>(MSG_COLUMN_COUNT)?, &marks),
+            })
+        )?.collect::<Result<Vec<_>, _>>()?;
+
+        let more = results.len() > size as usize;
+        results.truncate(size as usize);
+
+        Ok(SearchPage {
+            next: results.last().and_then(|result| result.msg.id).filter(|_| more),
+            results,
+        })
+    }).await?;
+
+    Ok(web::Json(page))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_filters_and_words() {
+        let terms = SearchTerms::parse("from:alice  party with:bob after:2024-01-31 before:1706659200s \"cake\"").unwrap();
+
+        assert_eq!(terms.text, ["party", "\"cake\""]);
+        assert_eq!(terms.from.as_deref(), Some("alice"));
+        assert_eq!(terms.with.as_deref(), Some("bob"));
+        assert_eq!(terms.after, Some(1_706_659_200_000));
+        assert_eq!(terms.before, Some(1_706_659_200_000));
+        assert_eq!(terms.fts_query(), "\"party\" \"\"\"cake\"\"\"");
+    }
+
+    #[test]
+    fn words_that_only_look_like_filters_are_searched() {
+        let terms = SearchTerms::parse("from: to:alice").unwrap();
+        assert_eq!(terms.text, ["from:", "to:alice"]);
+        assert_eq!(terms.from, None);
+    }
+
+    #[test]
+    fn parses_dates_and_times_with_units() {
+        assert_eq!(parse_time("1970-01-01"), Ok(0));
+        assert_eq!(parse_time("2024-02-29"), Ok(1_709_164_800_000));
+        assert_eq!(parse_time("2024-03-01"), Ok(1_709_251_200_000));
+        assert_eq!(parse_time("1706659200000ms"), Ok(1_706_659_200_000));
+        assert_eq!(parse_time("1706659200s"), Ok(1_706_659_200_000));
+    }
+
+    #[test]
+    fn rejects_bare_numbers_and_invalid_dates() {
+        for time in ["2024", "1706659200000", "2023-02-29", "2024-13-01", "2024-04-31", "1969-12-31", "24-01-31", "2024-1-31", "99999999999999999999s", "s", "ms", ""] {
+            assert!(parse_time(time).is_err(), "{time}");
+        }
+    }
+
+    #[test]
+    fn highlights_escaped_snippets() {
+        let marks = snippet_marks();
+        let snippet = format!("<img src=x onerror=alert(1)> {}party{} & more", marks.0, marks.1);
+        assert_eq!(highlight(&snippet, &marks), "&lt;img src=x onerror=alert(1)&gt; <mark>party</mark> &amp; more");
+    }
+
+    #[test]
+    fn marks_written_in_messages_are_only_text() {
+        let marks = snippet_marks();
+        let snippet = format!("<mark>fake</mark> {}real{}", marks.0, marks.1);
+        assert_eq!(highlight(&snippet, &marks), "&lt;mark&gt;fake&lt;/mark&gt; <mark>real</mark>");
+    }
+}
@@ -0,0 +1,175 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use actix_session::Session;
+use actix_web::{error, get, post, web, Responder};
+use rusqlite::{params, Transaction};
+use serde::{Deserialize, Serialize};
+
+use crate::{api::{auth::validate_session, msgs::{DEFAULT_MESSAGE_PAGE_SIZE, MAX_MESSAGE_PAGE_SIZE}}, db::{self, Pool}, ws::{policy::can_see, WsMessage, MSG_COLUMNS, MSG_COLUMN_COUNT}};
+
+#[derive(Debug, Deserialize)]
+pub struct QueryStarred {
+    size: Option<u32>,
+    /// The `next` of the previous page, to get the messages starred before those
+    before: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct Starred {
+    #[serde(flatten)]
+    msg: WsMessage,
+    starred_at: u64,
+    /// The name of the room it was sent to, so it can be shown without asking for the room
+    #[serde(skip_serializing_if = "Option::is_none")]
+    room_name: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct StarredPage {
+    msgs: Vec<Starred>,
+    /// Pass it as `before` to get the messages starred earlier, if there are any
+    next: Option<i64>,
+}
+
+/// Stars are only seen by the user who set them, so nobody else is told
+#[post("/star/{id}")]
+pub async fn star(session: Session, db: web::Data<Pool>, id: web::Path<i64>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let id = id.into_inner();
+
+    let starred = db::execute_immediate(&db, move |conn| {
+        add_star(conn, &user_id, id, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64)
+    }).await?;
+
+    if !starred {
+        return Err(error::ErrorNotFound("That message doesn't exist"));
+    }
+
+    Ok("Starred")
+}
+
+#[post("/unstar/{id}")]
+pub async fn unstar(session: Session, db: web::Data<Pool>, id: web::Path<i64>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let id = id.into_inner();
+
+    db::execute(&db, move |conn| {
+        conn.execute(
+            "DELETE FROM stars WHERE username = ?1 AND msg_id = ?2",
+            params![user_id, id]
+        )
+    }).await?;
+
+    Ok("Unstarred")
+}
+
+/// Starred messages from every conversation, the last starred first.
+/// The ones the user can't see anymore are left out
+#[get("/starred")]
+pub async fn get_starred(session: Session, db: web::Data<Pool>, query: web::Query<QueryStarred>) -> Result<impl Responder, error::Error> {
+    let user_id = validate_session(&session)?;
+    let size = query.size.unwrap_or(DEFAULT_MESSAGE_PAGE_SIZE).clamp(1, MAX_MESSAGE_PAGE_SIZE);
+    let before = query.before.unwrap_or(i64::MAX);
+
+    let page = db::execute(&db, move |conn| starred_page(conn, &user_id, before, size)).await?;
+
+    Ok(web::Json(page))
+}
+
+/// Stars the message for `user_id`, if they can see it
+fn add_star(conn: &Transaction, user_id: &str, id: i64, now: u64) -> Result<bool, rusqlite::Error> {
+    match WsMessage::load(conn, id)? {
+        Some(msg) if !msg.deleted && can_see(conn, user_id, &msg)? => (),
+        _ => return Ok(false),
+    }
+
+    conn.execute(
+        "INSERT OR IGNORE INTO stars (username, msg_id, created) VALUES (?1, ?2, ?3)",
+        params![user_id, id, now]
+    )?;
+
+    Ok(true)
+}
+
+/// Up to `size` messages starred by `user_id` before the star `before`
+fn starred_page(conn: &Transaction, user_id: &str, before: i64, size: u32) -> Result<StarredPage, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {MSG_COLUMNS}, stars.id, stars.created, rooms.name FROM stars
+        INNER JOIN msgs ON msgs.id = stars.msg_id
+        LEFT JOIN rooms ON rooms.id = msgs.room
+        WHERE stars.username = ?1 AND stars.id < ?2
+        AND msgs.deleted = 0
+        AND msgs.id NOT IN (SELECT msg_id FROM hidden_msgs WHERE username = ?1)
+        AND (msgs.room IS NULL OR msgs.room IN (SELECT room FROM room_members WHERE username = ?1))
+        ORDER BY stars.id DESC
+        LIMIT ?3;"
+    ))?;
+
+    // One more than asked for, to know if there are more
+    let mut rows = stmt.query_map(
+        params![user_id, before, size + 1],
+        |row| Ok((
+            row.get::<_, i64>(MSG_COLUMN_COUNT)?,
+            Starred {
+                msg: WsMessage::from_row(row)?,
+                starred_at: row.get(MSG_COLUMN_COUNT + 1)?,
+                room_name: row.get(MSG_COLUMN_COUNT + 2)?,
+            }
+        ))
+    )?.collect::<Result<Vec<_>, _>>()?;
+
+    let more = rows.len() > size as usize;
+    rows.truncate(size as usize);
+
+    Ok(StarredPage {
+        next: rows.last().map(|(position, _)| *position).filter(|_| more),
+        msgs: rows.into_iter().map(|(_, starred)| starred).collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn starred_messages() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch(
+            "INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', ''), ('carol', '');
+            INSERT INTO rooms (name, owner) VALUES ('r', 'alice');
+            INSERT INTO room_members (room, username) VALUES (1, 'alice'), (1, 'bob');
+            INSERT INTO msgs (sender, recv, msg, timestamp, room) VALUES 
+                ('alice', 'bob', 'one', 0, NULL), 
+                ('bob', 'alice', 'two', 0, NULL), 
+                ('alice', NULL, 'room', 0, 1), 
+                ('alice', 'carol', 'private', 0, NULL), 
+                ('bob', 'alice', 'hidden', 0, NULL);"
+        ).unwrap();
+        let tx = conn.transaction().unwrap();
+        let ids = |before: i64, size: u32| {
+            let page = starred_page(&tx, "bob", before, size).unwrap();
+            (page.msgs.iter().map(|starred| starred.msg.id.unwrap()).collect::<Vec<_>>(), page.next)
+        };
+
+        for id in [1, 2, 3, 5] {
+            assert!(add_star(&tx, "bob", id, 0).unwrap());
+        }
+        assert!(!add_star(&tx, "bob", 4, 0).unwrap());
+        // Starring it again keeps its place
+        assert!(add_star(&tx, "bob", 1, 0).unwrap());
+
+        assert_eq!(ids(i64::MAX, 10), (vec![5, 3, 2, 1], None));
+        assert_eq!(ids(i64::MAX, 3), (vec![5, 3, 2], Some(2)));
+        assert_eq!(ids(2, 3), (vec![1], None));
+        assert_eq!(starred_page(&tx, "bob", 4, 1).unwrap().msgs[0].room_name, Some("r".to_owned()));
+
+        // Only the ones they can still see
+        tx.execute_batch(
+            "INSERT INTO hidden_msgs (msg_id, username) VALUES (5, 'bob');
+            UPDATE msgs SET deleted = 1 WHERE id = 2;
+            DELETE FROM room_members WHERE username = 'bob';"
+        ).unwrap();
+        assert_eq!(ids(i64::MAX, 10), (vec![1], None));
+        assert!(starred_page(&tx, "alice", i64::MAX, 10).unwrap().msgs.is_empty());
+    }
+}
@@ -1,5 +1,6 @@
 use std::{fs::{self, File}, io::Write};
 
+use actix::Addr;
 use actix_session::Session;
 use actix_web::{error, get, post, web, Responder};
 use dataurl::DataUrl;
@@ -7,7 +8,7 @@ use log::warn;
 use rusqlite::params;
 use serde::{Deserialize, Serialize};
 
-use crate::db::{self, Pool};
+use crate::{db::{self, Pool}, ws::{ChatServer, Notice, PostNotice}};
 use super::auth::validate_session;
 
 #[derive(Serialize, Debug, Default, Clone)]
@@ -37,7 +38,7 @@ struct ImageData {
 }
 
 #[post("/upload-image")]
-pub async fn upload_image(session: Session, image_data: web::Json<ImageData>) -> Result<impl Responder, error::Error> {
+pub async fn upload_image(session: Session, image_data: web::Json<ImageData>, srv: web::Data<Addr<ChatServer>>) -> Result<impl Responder, error::Error> {
     let user_id = validate_session(&session)?;
     let data_url = DataUrl::parse(&image_data.data)
         .map_err(|_| error::ErrorBadRequest("No image uploaded"))?;
@@ -52,6 +53,11 @@ pub async fn upload_image(session: Session, image_data: web::Json<ImageData>) ->
         .map_err(|_| error::ErrorInternalServerError("Couldn't create image in the server"))?;
     file.write_all(bytes)
         .map_err(|_| error::ErrorInternalServerError("Couldn't save image in the server"))?;
+
+    // Everyone the user talks to is told in their chat
+    srv.send(PostNotice { sender: user_id, room: None, notice: Notice::PhotoChanged })
+        .await
+        .map_err(error::ErrorInternalServerError)??;
     
     Ok("done")
 }
@@ -0,0 +1,96 @@
+//! Admin commands, run instead of the server with `actix-server <command> <args>`.
+//! They work directly on the database, so they can be used while the server is down.
+
+use std::{fs, io::{self, BufWriter, Write}, process};
+
+use rusqlite::TransactionBehavior;
+
+use crate::{api::export::{export_batch, ExportFormat, EXPORT_BATCH_SIZE}, db::Pool};
+use import::{ImportFormat, Participants};
+
+mod import;
+
+const USAGE: &str = "Usage:
+    actix-server export <user> <other> [--format json|html|text]    Writes the conversation between two users to the standard output
+    actix-server import <user> <other> <file> [--format json|whatsapp] [--names <user's name> <other's name>]
+        Adds the messages of an export to the conversation between two users, the names are the ones they have in the file";
+
+/// Runs the command, or prints why it couldn't and exits with an error
+pub fn run(pool: &Pool, command: &str, args: &[String]) {
+    let res = match command {
+        "export" => export(pool, args),
+        "import" => import(pool, args),
+        _ => Err(format!("Unknown command {command}")),
+    };
+
+    if let Err(err) = res {
+        eprintln!("{err}\n\n{USAGE}");
+        process::exit(1);
+    }
+}
+
+/// Unlike the endpoint, it exports the messages any of the users deleted for themselves
+fn export(pool: &Pool, args: &[String]) -> Result<(), String> {
+    let (user, other, format) = match args {
+        [user, other] => (user, other, ExportFormat::default()),
+        [user, other, flag, format] if flag == "--format" => (user, other, format.parse()?),
+        _ => return Err("export needs two users".to_owned()),
+    };
+
+    let mut conn = pool.get().map_err(|err| err.to_string())?;
+    let mut out = BufWriter::new(io::stdout().lock());
+    let write_err = |err: io::Error| err.to_string();
+
+    out.write_all(format.header(user, other).as_bytes()).map_err(write_err)?;
+
+    let mut after = 0;
+    loop {
+        let tx = conn.transaction().map_err(|err| err.to_string())?;
+        let msgs = export_batch(&tx, user, other, after, false).map_err(|err| err.to_string())?;
+        drop(tx);
+
+        for msg in &msgs {
+            out.write_all(format.message(msg, user).as_bytes()).map_err(write_err)?;
+        }
+
+        match msgs.last().and_then(|msg| msg.id) {
+            Some(last) if msgs.len() == EXPORT_BATCH_SIZE as usize => after = last,
+            _ => break,
+        }
+    }
+
+    out.write_all(format.footer().as_bytes()).map_err(write_err)?;
+    out.flush().map_err(write_err)
+}
+
+fn import(pool: &Pool, args: &[String]) -> Result<(), String> {
+    let [user, other, path, options @ ..] = args else {
+        return Err("import needs two users and a file".to_owned());
+    };
+
+    let (mut format, mut names) = (ImportFormat::default(), (user, other));
+    let mut options = options.iter();
+    while let Some(option) = options.next() {
+        match (option.as_str(), options.next(), options.clone().next()) {
+            ("--format", Some(value), _) => format = value.parse()?,
+            ("--names", Some(user_name), Some(other_name)) => {
+                names = (user_name, other_name);
+                options.next();
+            }
+            _ => return Err(format!("Wrong option {option}")),
+        }
+    }
+
+    let text = fs::read_to_string(path).map_err(|err| format!("Couldn't read {path}: {err}"))?;
+    let msgs = import::parse(format, &text)?;
+    let participants = Participants { user, other, user_name: names.0, other_name: names.1 };
+
+    let mut conn = pool.get().map_err(|err| err.to_string())?;
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Immediate).map_err(|err| err.to_string())?;
+    // Nothing is kept if it fails, the transaction is rolled back when dropped
+    let summary = import::import(&tx, &participants, msgs).map_err(|err| err.to_string())??;
+    tx.commit().map_err(|err| err.to_string())?;
+
+    println!("Imported {} messages, {} were already there", summary.imported, summary.skipped);
+    Ok(())
+}
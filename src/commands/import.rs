@@ -0,0 +1,419 @@
+//! Chat history brought from an export of this server, or from a WhatsApp chat export.
+//! It's only done by an admin: anyone else could make up what the other user said.
+
+use std::{collections::{hash_map::Entry, HashMap}, str::FromStr};
+
+use rusqlite::{params, Transaction};
+
+use crate::{date, ws::{policy::user_exists, MessageStatus, WsMessage}};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImportFormat {
+    /// The JSON lines written by the export
+    #[default]
+    Json,
+    /// The text file of the "Export chat" option, from Android or iOS
+    Whatsapp,
+}
+
+impl FromStr for ImportFormat {
+    type Err = String;
+
+    fn from_str(format: &str) -> Result<Self, Self::Err> {
+        match format {
+            "json" => Ok(ImportFormat::Json),
+            "whatsapp" => Ok(ImportFormat::Whatsapp),
+            _ => Err(format!("Unknown format {format}, it has to be json or whatsapp")),
+        }
+    }
+}
+
+/// The two users of the conversation, and the names they have in the file, which don't need to be their usernames
+#[derive(Debug, Clone, Copy)]
+pub struct Participants<'a> {
+    pub user: &'a str,
+    pub other: &'a str,
+    pub user_name: &'a str,
+    pub other_name: &'a str,
+}
+
+#[derive(Debug, Default)]
+pub struct ImportSummary {
+    pub imported: usize,
+    /// Messages that were already in the conversation
+    pub skipped: usize,
+}
+
+/// Reads the messages of the file, the oldest first. Their sender is still the name in the file
+pub fn parse(format: ImportFormat, text: &str) -> Result<Vec<WsMessage>, String> {
+    let mut msgs = match format {
+        ImportFormat::Json => text.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(i, line)| serde_json::from_str::<WsMessage>(line).map_err(|err| format!("Line {}: {err}", i + 1)))
+            // Only one-to-one chats are exported, and there's nothing to bring of a deleted message
+            .filter(|msg| msg.as_ref().map_or(true, |msg| msg.room.is_none() && !msg.deleted))
+            .collect::<Result<Vec<_>, _>>()?,
+        ImportFormat::Whatsapp => whatsapp(text)?,
+    };
+    msgs.sort_by_key(|msg| msg.time);
+
+    Ok(msgs)
+}
+
+/// Adds the messages to the conversation between the two users in a single transaction,
+/// leaving out the ones that were already in it.
+/// Conversations are listed by id, so it fails if a new message is older than the last one already there
+pub fn import(conn: &Transaction, participants: &Participants, msgs: Vec<WsMessage>) -> Result<Result<ImportSummary, String>, rusqlite::Error> {
+    let Participants { user, other, user_name, other_name } = *participants;
+    for username in [user, other] {
+        if !user_exists(conn, username)? {
+            return Ok(Err(format!("There's no user called {username}")));
+        }
+    }
+
+    // Every sender is checked before anything is written
+    let mut senders = Vec::with_capacity(msgs.len());
+    for msg in &msgs {
+        let sender = match &msg.sender {
+            name if name == user_name => user,
+            name if name == other_name => other,
+            name => return Ok(Err(format!("{name} isn't one of the users of the conversation"))),
+        };
+        senders.push(sender);
+    }
+
+    let newest: Option<u64> = conn.query_row(
+        "SELECT MAX(timestamp) FROM msgs
+        WHERE room IS NULL AND ((sender = ?1 AND recv = ?2) OR (sender = ?2 AND recv = ?1))",
+        params![user, other],
+        |row| row.get(0)
+    )?;
+
+    let mut count_stmt = conn.prepare(
+        "SELECT COUNT(*) FROM msgs
+        WHERE room IS NULL AND sender = ?1 AND recv = ?2 AND timestamp = ?3 AND msg = ?4"
+    )?;
+    let mut insert_stmt = conn.prepare(
+        "INSERT INTO msgs (sender, recv, msg, timestamp, reply_to, delivered_at, read_at, edited_at, forwarded_from, kind)
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10) RETURNING (id)"
+    )?;
+
+    // The same message can be sent twice, so only as many copies as there already are get skipped
+    let mut existing: HashMap<(&str, u64, &str), (usize, usize)> = HashMap::new();
+    // The new ids of the messages in the file, for the replies to them
+    let mut ids = HashMap::new();
+    let mut summary = ImportSummary::default();
+
+    for (msg, sender) in msgs.iter().zip(senders) {
+        let recv = if sender == user { other } else { user };
+
+        let (copies, seen) = match existing.entry((sender, msg.time, msg.msg.as_str())) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert((
+                count_stmt.query_row(params![sender, recv, msg.time, msg.msg], |row| row.get(0))?,
+                0
+            )),
+        };
+        *seen += 1;
+        if *seen <= *copies {
+            summary.skipped += 1;
+            continue;
+        }
+        if newest.is_some_and(|newest| msg.time < newest) {
+            return Ok(Err("The file has messages older than the last one of the conversation, they'd be listed after it. Only newer messages can be imported".to_owned()));
+        }
+
+        let delivered = matches!(msg.status, MessageStatus::Delivered | MessageStatus::Read) || msg.read;
+        let read = msg.status == MessageStatus::Read || msg.read;
+        let id: i64 = insert_stmt.query_row(
+            params![
+                sender,
+                recv,
+                msg.msg,
+                msg.time,
+                msg.reply_to.as_ref().and_then(|quote| ids.get(&quote.id)),
+                delivered.then_some(msg.time),
+                read.then_some(msg.time),
+                msg.edited_at,
+                msg.forwarded_from,
+                msg.kind.to_sql()?
+            ],
+            |row| row.get(0)
+        )?;
+
+        if let Some(old) = msg.id {
+            ids.insert(old, id);
+        }
+        summary.imported += 1;
+    }
+
+    Ok(Ok(summary))
+}
+
+/// A line that starts a message, or one written by WhatsApp itself if there's no sender
+struct WhatsappLine<'a> {
+    date: [u32; 3],
+    time: u64,
+    sender: Option<(&'a str, &'a str)>,
+}
+
+/// Lines like `18/10/2026, 11:44 - Alice: Hi` from Android, or `[18/10/2026, 11:44:05] Alice: Hi` from iOS.
+/// The time is taken as UTC, and the lines that don't start a message are part of the one before
+fn whatsapp(text: &str) -> Result<Vec<WsMessage>, String> {
+    let lines: Vec<(&str, Option<WhatsappLine>)> = text.lines().map(|line| (line, whatsapp_line(line))).collect();
+
+    // The order of the day and the month depends on the phone, it's found from the dates that can only be read one way
+    let dates = lines.iter().filter_map(|(_, parsed)| parsed.as_ref().map(|parsed| parsed.date));
+    let day_first = dates.clone().any(|[first, _, _]| first > 12) || !dates.clone().any(|[_, second, _]| second > 12);
+
+    let mut msgs: Vec<WsMessage> = Vec::new();
+    let mut in_message = false;
+    for (i, (line, parsed)) in lines.into_iter().enumerate() {
+        let Some(WhatsappLine { date: [first, second, year], time, sender }) = parsed else {
+            if let Some(msg) = msgs.last_mut().filter(|_| in_message) {
+                msg.msg.push('\n');
+                msg.msg.push_str(line);
+            }
+            continue;
+        };
+
+        in_message = sender.is_some();
+        let Some((sender, text)) = sender else {
+            continue;
+        };
+
+        let (day, month) = if day_first { (first, second) } else { (second, first) };
+        let year = if year < 100 { year + 2000 } else { year };
+        // Before 1970 there are no timestamps, and past 9999 they don't fit
+        let Some(date) = date::to_millis(year, month, day) else {
+            return Err(format!("Line {}: {first}/{second}/{year} isn't a valid date", i + 1));
+        };
+
+        msgs.push(WsMessage {
+            msg: text.trim_start_matches('\u{200e}').to_owned(),
+            sender: sender.to_owned(),
+            time: date + time,
+            read: true,
+            status: MessageStatus::Read,
+            ..Default::default()
+        });
+    }
+
+    Ok(msgs)
+}
+
+fn whatsapp_line(line: &str) -> Option<WhatsappLine<'_>> {
+    let line = line.trim_start_matches('\u{200e}');
+    let (bracketed, line) = match line.strip_prefix('[') {
+        Some(line) => (true, line),
+        None => (false, line),
+    };
+
+    let (date, line) = line.split_once(", ")?;
+    let date: Vec<u32> = date.split(['/', '.', '-']).map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let date: [u32; 3] = date.try_into().ok()?;
+    if date.iter().take(2).any(|part| !(1..=31).contains(part)) {
+        return None;
+    }
+
+    let (time, rest) = line.split_once(if bracketed { "] " } else { " - " })?;
+    let time = time.replace(['\u{202f}', '\u{a0}'], " ");
+    let (time, afternoon) = match time.trim().rsplit_once(' ') {
+        Some((time, "PM" | "pm")) => (time, Some(true)),
+        Some((time, "AM" | "am")) => (time, Some(false)),
+        _ => (time.trim(), None),
+    };
+    let parts: Vec<u64> = time.split(':').map(|part| part.parse().ok()).collect::<Option<_>>()?;
+    let (hours, minutes, seconds) = match parts[..] {
+        [hours, minutes] => (hours, minutes, 0),
+        [hours, minutes, seconds] => (hours, minutes, seconds),
+        _ => return None,
+    };
+    let hours = match afternoon {
+        Some(afternoon) => hours % 12 + if afternoon { 12 } else { 0 },
+        None => hours,
+    };
+    if hours > 23 || minutes > 59 || seconds > 59 {
+        return None;
+    }
+
+    Some(WhatsappLine {
+        date,
+        time: ((hours * 60 + minutes) * 60 + seconds) * 1000,
+        sender: rest.trim_start_matches('\u{200e}').split_once(": "),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::{date::DAY, db};
+
+    const HOUR: u64 = 3_600_000;
+    /// 2026-10-18T00:00:00Z
+    const OCT_18: u64 = 20_744 * DAY;
+
+    fn texts(msgs: &[WsMessage]) -> Vec<(&str, &str, u64)> {
+        msgs.iter().map(|msg| (msg.sender.as_str(), msg.msg.as_str(), msg.time)).collect()
+    }
+
+    #[test]
+    fn whatsapp_android() {
+        let text = "18/10/2026, 11:44 - Messages and calls are end-to-end encrypted.
+18/10/2026, 11:44 - Alice: Hi
+19/10/2026, 09:05 - Bob: Hello
+how are you?
+
+18/10/2026, 23:59 - Alice: <Media omitted>";
+        let msgs = parse(ImportFormat::Whatsapp, text).unwrap();
+
+        assert_eq!(texts(&msgs), vec![
+            ("Alice", "Hi", OCT_18 + 11 * HOUR + 44 * 60_000),
+            ("Alice", "<Media omitted>", OCT_18 + 23 * HOUR + 59 * 60_000),
+            ("Bob", "Hello\nhow are you?\n", OCT_18 + DAY + 9 * HOUR + 5 * 60_000),
+        ]);
+        assert!(msgs.iter().all(|msg| msg.read && msg.status == MessageStatus::Read));
+    }
+
+    #[test]
+    fn whatsapp_ios() {
+        let text = "\u{200e}[18.10.26, 11:44:05] Alice: \u{200e}Hi
+[18.10.26, 11:44:30] Bob: Hello";
+        let msgs = parse(ImportFormat::Whatsapp, text).unwrap();
+
+        assert_eq!(texts(&msgs), vec![
+            ("Alice", "Hi", OCT_18 + 11 * HOUR + 44 * 60_000 + 5_000),
+            ("Bob", "Hello", OCT_18 + 11 * HOUR + 44 * 60_000 + 30_000),
+        ]);
+    }
+
+    #[test]
+    fn whatsapp_month_first_and_twelve_hours() {
+        let text = "10/18/26, 12:05\u{202f}AM - Alice: Late
+10/18/26, 12:05\u{202f}PM - Bob: Noon
+10/17/26, 1:00 pm - Alice: Before";
+        let msgs = parse(ImportFormat::Whatsapp, text).unwrap();
+
+        assert_eq!(texts(&msgs), vec![
+            ("Alice", "Before", OCT_18 - DAY + 13 * HOUR),
+            ("Alice", "Late", OCT_18 + 5 * 60_000),
+            ("Bob", "Noon", OCT_18 + 12 * HOUR + 5 * 60_000),
+        ]);
+    }
+
+    #[test]
+    fn whatsapp_ambiguous_dates_are_day_first() {
+        let msgs = parse(ImportFormat::Whatsapp, "01/02/2026, 10:00 - Alice: Hi").unwrap();
+        assert_eq!(msgs[0].time, date::to_millis(2026, 2, 1).unwrap() + 10 * HOUR);
+    }
+
+    #[test]
+    fn whatsapp_invalid_dates() {
+        for text in [
+            "31/02/2026, 10:00 - Alice: Hi",
+            "29/02/2025, 10:00 - Alice: Hi",
+            "31/12/1969, 23:59 - Alice: Hi",
+            "01/01/4294967295, 10:00 - Alice: Hi",
+            // The first date says it's day first
+            "13/01/2026, 10:00 - Alice: Hi\n01/13/2026, 10:00 - Bob: Hi",
+        ] {
+            assert!(parse(ImportFormat::Whatsapp, text).is_err(), "{text}");
+        }
+        assert!(parse(ImportFormat::Whatsapp, "29/02/2024, 10:00 - Alice: Hi").is_ok());
+    }
+
+    #[test]
+    fn whatsapp_invalid_times_are_text() {
+        let text = "18/10/2026, 10:00 - Alice: Hi
+18/10/2026, 24:00 - Alice: still the first";
+        let msgs = parse(ImportFormat::Whatsapp, text).unwrap();
+        assert_eq!(texts(&msgs), vec![("Alice", "Hi\n18/10/2026, 24:00 - Alice: still the first", OCT_18 + 10 * HOUR)]);
+    }
+
+    #[test]
+    fn json_export() {
+        let msg = |id, sender: &str, time, room, deleted| serde_json::to_string(&WsMessage {
+            id: Some(id),
+            msg: format!("message {id}"),
+            sender: sender.to_owned(),
+            time,
+            room,
+            deleted,
+            ..Default::default()
+        }).unwrap();
+        let text = [
+            msg(2, "bob", 2000, None, false),
+            String::new(),
+            msg(1, "alice", 1000, None, false),
+            msg(3, "alice", 3000, Some(1), false),
+            msg(4, "bob", 4000, None, true),
+        ].join("\n");
+        let msgs = parse(ImportFormat::Json, &text).unwrap();
+        assert_eq!(texts(&msgs), vec![("alice", "message 1", 1000), ("bob", "message 2", 2000)]);
+
+        let err = parse(ImportFormat::Json, &format!("{}\nnot json", msg(1, "alice", 1000, None, false))).unwrap_err();
+        assert!(err.starts_with("Line 2:"), "{err}");
+    }
+
+    fn conversation() -> rusqlite::Connection {
+        let conn = db::open_in_memory();
+        conn.execute_batch("INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', '');").unwrap();
+        conn
+    }
+
+    fn run(conn: &mut rusqlite::Connection, text: &str) -> Result<ImportSummary, String> {
+        let participants = Participants { user: "alice", other: "bob", user_name: "Alice", other_name: "Bob" };
+        let tx = conn.transaction().unwrap();
+        let summary = import(&tx, &participants, parse(ImportFormat::Whatsapp, text).unwrap()).unwrap();
+        if summary.is_ok() {
+            tx.commit().unwrap();
+        }
+        summary
+    }
+
+    fn stored(conn: &rusqlite::Connection) -> Vec<(String, String)> {
+        conn.prepare("SELECT sender, msg FROM msgs ORDER BY id").unwrap()
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?))).unwrap()
+            .collect::<Result<_, _>>().unwrap()
+    }
+
+    #[test]
+    fn imports_once() {
+        let mut conn = conversation();
+        let text = "18/10/2026, 10:00 - Alice: Hi
+18/10/2026, 10:00 - Alice: Hi
+18/10/2026, 10:01 - Bob: Hello";
+
+        let summary = run(&mut conn, text).unwrap();
+        assert_eq!((summary.imported, summary.skipped), (3, 0));
+
+        // A later export of the same chat only brings what's new
+        let summary = run(&mut conn, &format!("{text}\n18/10/2026, 10:02 - Bob: Bye")).unwrap();
+        assert_eq!((summary.imported, summary.skipped), (1, 3));
+
+        assert_eq!(stored(&conn), vec![
+            ("alice".to_owned(), "Hi".to_owned()),
+            ("alice".to_owned(), "Hi".to_owned()),
+            ("bob".to_owned(), "Hello".to_owned()),
+            ("bob".to_owned(), "Bye".to_owned()),
+        ]);
+    }
+
+    #[test]
+    fn rejects_messages_older_than_the_conversation() {
+        let mut conn = conversation();
+        run(&mut conn, "18/10/2026, 10:00 - Alice: Hi").unwrap();
+
+        assert!(run(&mut conn, "17/10/2026, 10:00 - Bob: Yesterday\n18/10/2026, 11:00 - Bob: Later").is_err());
+        assert_eq!(stored(&conn), vec![("alice".to_owned(), "Hi".to_owned())]);
+    }
+
+    #[test]
+    fn rejects_unknown_senders() {
+        let mut conn = conversation();
+        assert!(run(&mut conn, "18/10/2026, 10:00 - Carol: Hi").is_err());
+        assert!(stored(&conn).is_empty());
+    }
+}
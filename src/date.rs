@@ -0,0 +1,79 @@
+//! Dates in UTC, in the proleptic Gregorian calendar, with the algorithms from http://howardhinnant.github.io/date_algorithms.html
+
+/// Milliseconds in a day
+pub const DAY: u64 = 24 * 60 * 60 * 1000;
+
+/// Milliseconds since 1970-01-01 at the start of the day, if it's a real date from 1970 to 9999
+pub fn to_millis(year: u32, month: u32, day: u32) -> Option<u64> {
+    let valid = (1970..=9999).contains(&year) && (1..=12).contains(&month) && (1..=days_in_month(year, month)).contains(&day);
+    valid.then(|| days_from_civil(year as i64, month, day) as u64 * DAY)
+}
+
+fn days_in_month(year: u32, month: u32) -> u32 {
+    match month {
+        2 if year.is_multiple_of(4) && (!year.is_multiple_of(100) || year.is_multiple_of(400)) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Days since 1970-01-01
+pub fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+/// The year, month and day that are a number of days since 1970-01-01
+pub fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_days() {
+        for (date, days) in [((1970, 1, 1), 0), ((1969, 12, 31), -1), ((2000, 3, 1), 11_017), ((2024, 2, 29), 19_782), ((2026, 10, 18), 20_744)] {
+            assert_eq!(days_from_civil(date.0, date.1, date.2), days, "{date:?}");
+            assert_eq!(civil_from_days(days), date);
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for days in -800_000..800_000 {
+            let (year, month, day) = civil_from_days(days);
+            assert_eq!(days_from_civil(year, month, day), days);
+        }
+    }
+
+    #[test]
+    fn only_real_dates() {
+        assert_eq!(to_millis(1970, 1, 1), Some(0));
+        assert_eq!(to_millis(2024, 1, 31), Some(19_753 * DAY));
+        assert!(to_millis(2024, 2, 29).is_some());
+        assert!(to_millis(2000, 2, 29).is_some());
+        assert!(to_millis(9999, 12, 31).is_some());
+
+        for (year, month, day) in [(2025, 2, 29), (1900, 2, 29), (2024, 4, 31), (2024, 13, 1), (2024, 0, 1), (2024, 1, 0), (1969, 12, 31), (10_000, 1, 1)] {
+            assert_eq!(to_millis(year, month, day), None, "{year}-{month}-{day}");
+        }
+    }
+}
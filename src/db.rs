@@ -3,10 +3,438 @@ use std::{fs, path::Path};
 use actix_web::web;
 use log::{debug, info};
 use r2d2_sqlite::SqliteConnectionManager;
-use rusqlite::Transaction;
+use rusqlite::{Connection, Transaction, TransactionBehavior};
 
 pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 
+// Schema changes applied on top of the base tables, tracked with `PRAGMA user_version`.
+// Only ever append to this list, the position of each migration is its version.
+const MIGRATIONS: &[&str] = &[
+    // Group conversations
+    "
+    CREATE TABLE rooms (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        name        TEXT NOT NULL,
+        owner       TEXT NOT NULL,
+        created     INTEGER,
+        FOREIGN KEY(owner) 
+            REFERENCES users (username)
+    );
+
+    CREATE TABLE room_members (
+        room        INTEGER NOT NULL,
+        username    TEXT NOT NULL,
+        unread      INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY(room, username),
+        FOREIGN KEY(room) 
+            REFERENCES rooms (id)
+        FOREIGN KEY(username) 
+            REFERENCES users (username)
+    );
+    CREATE INDEX room_members_username_index 
+    ON room_members (username);
+
+    ALTER TABLE msgs ADD COLUMN room INTEGER REFERENCES rooms (id);
+    CREATE INDEX msgs_room_index 
+    ON msgs (room);
+    ",
+    // Server generated message ids
+    "
+    CREATE TABLE msgs_new (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        msg         TEXT,
+        timestamp   INTEGER,
+        sender      TEXT,
+        recv        TEXT,
+        read        INTEGER,
+        room        INTEGER,
+        FOREIGN KEY(sender) 
+            REFERENCES users (username)
+        FOREIGN KEY(recv) 
+            REFERENCES users (username)
+        FOREIGN KEY(room) 
+            REFERENCES rooms (id)
+    );
+    INSERT INTO msgs_new (msg, timestamp, sender, recv, read, room)
+    SELECT msg, timestamp, sender, recv, read, room FROM msgs ORDER BY timestamp, rowid;
+
+    DROP TABLE msgs;
+    ALTER TABLE msgs_new RENAME TO msgs;
+
+    CREATE INDEX msgs_sender_index 
+    ON msgs (sender);
+    CREATE INDEX msgs_recv_index 
+    ON msgs (recv);
+    CREATE INDEX msgs_room_index 
+    ON msgs (room);
+    ",
+    // Blocked users
+    "
+    CREATE TABLE blocks (
+        blocker     TEXT NOT NULL,
+        blocked     TEXT NOT NULL,
+        PRIMARY KEY(blocker, blocked),
+        FOREIGN KEY(blocker) 
+            REFERENCES users (username)
+        FOREIGN KEY(blocked) 
+            REFERENCES users (username)
+    );
+    ",
+    // Message edits
+    "
+    ALTER TABLE msgs ADD COLUMN edited_at INTEGER;
+
+    CREATE TABLE msg_edits (
+        msg_id      INTEGER NOT NULL,
+        msg         TEXT,
+        edited_at   INTEGER NOT NULL,
+        FOREIGN KEY(msg_id) 
+            REFERENCES msgs (id)
+    );
+    CREATE INDEX msg_edits_msg_id_index 
+    ON msg_edits (msg_id);
+    ",
+    // Deleted messages
+    "
+    ALTER TABLE msgs ADD COLUMN deleted INTEGER NOT NULL DEFAULT 0;
+
+    CREATE TABLE hidden_msgs (
+        msg_id      INTEGER NOT NULL,
+        username    TEXT NOT NULL,
+        PRIMARY KEY(msg_id, username),
+        FOREIGN KEY(msg_id) 
+            REFERENCES msgs (id)
+        FOREIGN KEY(username) 
+            REFERENCES users (username)
+    );
+    ",
+    // Replies
+    "
+    ALTER TABLE msgs ADD COLUMN reply_to INTEGER REFERENCES msgs (id);
+    ",
+    // Reactions
+    "
+    CREATE TABLE reactions (
+        msg_id      INTEGER NOT NULL,
+        username    TEXT NOT NULL,
+        emoji       TEXT NOT NULL,
+        created     INTEGER,
+        PRIMARY KEY(msg_id, username, emoji),
+        FOREIGN KEY(msg_id) 
+            REFERENCES msgs (id)
+        FOREIGN KEY(username) 
+            REFERENCES users (username)
+    );
+    ",
+    // Delivery and read receipts
+    "
+    ALTER TABLE msgs ADD COLUMN delivered_at INTEGER;
+    ALTER TABLE msgs ADD COLUMN read_at INTEGER;
+    UPDATE msgs SET delivered_at = timestamp, read_at = timestamp WHERE read = 1;
+    ALTER TABLE msgs DROP COLUMN read;
+
+    ALTER TABLE room_members ADD COLUMN last_read INTEGER NOT NULL DEFAULT 0;
+    UPDATE room_members SET last_read = COALESCE((
+        SELECT MAX(id) FROM msgs 
+        WHERE msgs.room = room_members.room AND msgs.sender != room_members.username AND (
+            SELECT COUNT(*) FROM msgs AS newer 
+            WHERE newer.room = msgs.room AND newer.sender != room_members.username AND newer.id > msgs.id
+        ) >= room_members.unread
+    ), 0);
+    ALTER TABLE room_members DROP COLUMN unread;
+    ",
+    // Idempotent sends
+    "
+    ALTER TABLE msgs ADD COLUMN client_id TEXT;
+    CREATE UNIQUE INDEX msgs_client_id_index ON msgs (sender, client_id) WHERE client_id IS NOT NULL;
+    ",
+    // Event log to sync the clients
+    "
+    CREATE TABLE events (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        username    TEXT NOT NULL,
+        event       TEXT NOT NULL,
+        created     INTEGER NOT NULL,
+        FOREIGN KEY(username) 
+            REFERENCES users (username)
+    );
+    CREATE INDEX events_username_index ON events (username, id);
+    CREATE INDEX events_created_index ON events (created);
+
+    ALTER TABLE users ADD COLUMN acked_event INTEGER;
+    ",
+    // Keyset pagination
+    "
+    DROP INDEX msgs_sender_index;
+    DROP INDEX msgs_room_index;
+    CREATE INDEX msgs_conversation_index 
+    ON msgs (sender, recv, id);
+    CREATE INDEX msgs_room_id_index 
+    ON msgs (room, id);
+    ",
+    // Full-text search, kept in sync with the messages
+    "
+    CREATE VIRTUAL TABLE msgs_fts USING fts5(msg, content='msgs', content_rowid='id');
+    INSERT INTO msgs_fts (msgs_fts) VALUES ('rebuild');
+
+    CREATE TRIGGER msgs_fts_insert AFTER INSERT ON msgs BEGIN
+        INSERT INTO msgs_fts (rowid, msg) VALUES (new.id, new.msg);
+    END;
+    CREATE TRIGGER msgs_fts_delete AFTER DELETE ON msgs BEGIN
+        INSERT INTO msgs_fts (msgs_fts, rowid, msg) VALUES ('delete', old.id, old.msg);
+    END;
+    CREATE TRIGGER msgs_fts_update AFTER UPDATE OF msg ON msgs BEGIN
+        INSERT INTO msgs_fts (msgs_fts, rowid, msg) VALUES ('delete', old.id, old.msg);
+        INSERT INTO msgs_fts (rowid, msg) VALUES (new.id, new.msg);
+    END;
+    ",
+    // Attachments
+    "
+    CREATE TABLE attachments (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        hash        TEXT NOT NULL,
+        mime        TEXT NOT NULL,
+        size        INTEGER NOT NULL,
+        width       INTEGER,
+        height      INTEGER,
+        name        TEXT,
+        uploader    TEXT NOT NULL,
+        created     INTEGER NOT NULL,
+        FOREIGN KEY(uploader) 
+            REFERENCES users (username)
+    );
+
+    CREATE TABLE msg_attachments (
+        msg_id          INTEGER NOT NULL,
+        attachment_id   INTEGER NOT NULL,
+        position        INTEGER NOT NULL,
+        PRIMARY KEY(msg_id, attachment_id),
+        FOREIGN KEY(msg_id) 
+            REFERENCES msgs (id)
+        FOREIGN KEY(attachment_id) 
+            REFERENCES attachments (id)
+    );
+    CREATE INDEX msg_attachments_attachment_id_index 
+    ON msg_attachments (attachment_id);
+    ",
+    // Voice notes
+    "
+    ALTER TABLE attachments ADD COLUMN duration INTEGER;
+    ALTER TABLE attachments ADD COLUMN waveform TEXT;
+    ",
+    // Scheduled messages
+    "
+    CREATE TABLE scheduled_msgs (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        msg         TEXT NOT NULL,
+        sender      TEXT NOT NULL,
+        recv        TEXT,
+        room        INTEGER,
+        reply_to    INTEGER,
+        attachments TEXT NOT NULL DEFAULT '[]',
+        send_at     INTEGER NOT NULL,
+        created     INTEGER NOT NULL,
+        error       TEXT,
+        FOREIGN KEY(sender) 
+            REFERENCES users (username)
+        FOREIGN KEY(recv) 
+            REFERENCES users (username)
+        FOREIGN KEY(room) 
+            REFERENCES rooms (id)
+    );
+    CREATE INDEX scheduled_msgs_sender_index 
+    ON scheduled_msgs (sender, send_at);
+    CREATE INDEX scheduled_msgs_send_at_index 
+    ON scheduled_msgs (send_at);
+    ",
+    // Disappearing messages
+    "
+    CREATE TABLE chat_settings (
+        user1               TEXT NOT NULL,
+        user2               TEXT NOT NULL,
+        disappear_after     INTEGER,
+        disappear_on_read   INTEGER NOT NULL DEFAULT 0,
+        PRIMARY KEY(user1, user2),
+        FOREIGN KEY(user1) 
+            REFERENCES users (username)
+        FOREIGN KEY(user2) 
+            REFERENCES users (username)
+    );
+
+    ALTER TABLE rooms ADD COLUMN disappear_after INTEGER;
+    ALTER TABLE rooms ADD COLUMN disappear_on_read INTEGER NOT NULL DEFAULT 0;
+
+    ALTER TABLE msgs ADD COLUMN disappear_after INTEGER;
+    ALTER TABLE msgs ADD COLUMN expires_at INTEGER;
+    CREATE INDEX msgs_expires_at_index 
+    ON msgs (expires_at) WHERE expires_at IS NOT NULL;
+    ",
+    // Pinned messages
+    "
+    CREATE TABLE pins (
+        msg_id      INTEGER NOT NULL,
+        pinned_by   TEXT NOT NULL,
+        created     INTEGER NOT NULL,
+        PRIMARY KEY(msg_id),
+        FOREIGN KEY(msg_id) 
+            REFERENCES msgs (id)
+        FOREIGN KEY(pinned_by) 
+            REFERENCES users (username)
+    );
+    ",
+    // Starred messages
+    "
+    CREATE TABLE stars (
+        id          INTEGER PRIMARY KEY AUTOINCREMENT,
+        username    TEXT NOT NULL,
+        msg_id      INTEGER NOT NULL,
+        created     INTEGER NOT NULL,
+        UNIQUE(username, msg_id),
+        FOREIGN KEY(username) 
+            REFERENCES users (username)
+        FOREIGN KEY(msg_id) 
+            REFERENCES msgs (id)
+    );
+    CREATE INDEX stars_msg_id_index 
+    ON stars (msg_id);
+    ",
+    // Forwarded messages
+    "
+    ALTER TABLE msgs ADD COLUMN forwarded_from TEXT;
+    ",
+    // Drafts
+    "
+    CREATE TABLE drafts (
+        username    TEXT NOT NULL,
+        recv        TEXT,
+        room        INTEGER,
+        msg         TEXT NOT NULL,
+        updated     INTEGER NOT NULL,
+        FOREIGN KEY(username) 
+            REFERENCES users (username)
+        FOREIGN KEY(recv) 
+            REFERENCES users (username)
+        FOREIGN KEY(room) 
+            REFERENCES rooms (id)
+    );
+    CREATE INDEX drafts_username_index 
+    ON drafts (username, recv, room);
+    ",
+    // Polls
+    "
+    CREATE TABLE polls (
+        msg_id      INTEGER PRIMARY KEY,
+        question    TEXT NOT NULL,
+        options     TEXT NOT NULL,
+        multiple    INTEGER NOT NULL DEFAULT 0,
+        closes_at   INTEGER,
+        FOREIGN KEY(msg_id) 
+            REFERENCES msgs (id)
+    );
+
+    CREATE TABLE poll_votes (
+        msg_id      INTEGER NOT NULL,
+        option      INTEGER NOT NULL,
+        username    TEXT NOT NULL,
+        created     INTEGER NOT NULL,
+        PRIMARY KEY(msg_id, option, username),
+        FOREIGN KEY(msg_id) 
+            REFERENCES polls (msg_id)
+        FOREIGN KEY(username) 
+            REFERENCES users (username)
+    );
+    ",
+    // Locations, contact cards and system notices
+    "
+    ALTER TABLE msgs ADD COLUMN kind TEXT;
+    ",
+    // Events by the message they are about, to forget them with it
+    "
+    CREATE INDEX events_msg_id_index 
+    ON events (event ->> '$.id');
+    CREATE INDEX events_reply_to_index 
+    ON events (event ->> '$.reply_to.id');
+    ",
+    // Pruned events of each user. Before this, only the oldest event left is known
+    "
+    ALTER TABLE users ADD COLUMN pruned_event INTEGER;
+    UPDATE users SET pruned_event = COALESCE(
+        (SELECT MIN(id) - 1 FROM events), 
+        (SELECT seq FROM sqlite_sequence WHERE name = 'events')
+    );
+    ",
+    // Acknowledged events of each device, the ones from before are kept for the clients that don't say theirs
+    "
+    CREATE TABLE device_cursors (
+        username    TEXT NOT NULL,
+        device      TEXT NOT NULL,
+        acked_event INTEGER NOT NULL,
+        PRIMARY KEY(username, device),
+        FOREIGN KEY(username) 
+            REFERENCES users (username)
+    );
+    INSERT INTO device_cursors (username, device, acked_event) 
+    SELECT username, '', acked_event FROM users WHERE acked_event IS NOT NULL;
+
+    ALTER TABLE users DROP COLUMN acked_event;
+    ",
+    // Index of senders made again on every start until now, keyset pagination dropped it
+    "
+    DROP INDEX IF EXISTS msgs_sender_index;
+    ",
+    // One draft per chat
+    "
+    DELETE FROM drafts WHERE rowid NOT IN (
+        SELECT rowid FROM (
+            SELECT rowid, ROW_NUMBER() OVER (PARTITION BY username, recv, room ORDER BY updated DESC, rowid DESC) AS newest 
+            FROM drafts
+        ) WHERE newest = 1
+    );
+    DROP INDEX drafts_username_index;
+    CREATE UNIQUE INDEX drafts_chat_index 
+    ON drafts (username, COALESCE(recv, ''), COALESCE(room, 0));
+    ",
+];
+
+/// Tables from before the migrations. Later indexes and columns are left to the migrations,
+/// anything made here again would undo the ones that dropped it
+const BASE_TABLES: &str = "
+    CREATE TABLE IF NOT EXISTS users (
+	username	TEXT NOT NULL UNIQUE,
+	password	TEXT NOT NULL,
+        last_time   INTEGER,
+        bio         TEXT,
+	PRIMARY KEY(username)
+    );
+    
+    CREATE TABLE IF NOT EXISTS contacts (
+        user1 TEXT NOT NULL,
+        user2 TEXT NOT NULL,
+        FOREIGN KEY(user1) 
+    		REFERENCES users (username)
+        FOREIGN KEY(user2) 
+    		REFERENCES users (username)
+    );
+    CREATE INDEX IF NOT EXISTS contacts_user1_index 
+    ON contacts (user1);
+    CREATE INDEX IF NOT EXISTS contacts_user2_index 
+    ON contacts (user2);
+
+    CREATE TABLE IF NOT EXISTS msgs (
+    	msg		TEXT,
+    	timestamp	INTEGER,
+    	sender	TEXT,
+    	recv	TEXT,
+        read    INTEGER,
+    	FOREIGN KEY(sender) 
+    		REFERENCES users (username)
+    	FOREIGN KEY(recv) 
+            REFERENCES users (username)
+    );
+    CREATE INDEX IF NOT EXISTS msgs_recv_index 
+    ON msgs (recv);
+    ";
+
 pub fn init_database() -> Result<Pool, actix_web::error::Error> {
     if !Path::new("data").exists() {
         fs::create_dir("data/").unwrap();
@@ -20,57 +448,57 @@ pub fn init_database() -> Result<Pool, actix_web::error::Error> {
     let pool = Pool::new(manager)
         .map_err(|_| actix_web::error::ErrorInternalServerError("Couldn't create new connection pool"))?;
 
-    pool.get()
-        .map_err(|err| actix_web::error::ErrorInternalServerError(err))?
-        .execute_batch(
-            "
-            BEGIN;
-            
-            CREATE TABLE IF NOT EXISTS users (
-	            username	TEXT NOT NULL UNIQUE,
-	            password	TEXT NOT NULL,
-                last_time   INTEGER,
-                bio         TEXT,
-	            PRIMARY KEY(username)
-            );
-            
-            CREATE TABLE IF NOT EXISTS contacts (
-                user1 TEXT NOT NULL,
-                user2 TEXT NOT NULL,
-                FOREIGN KEY(user1) 
-            		REFERENCES users (username)
-                FOREIGN KEY(user2) 
-            		REFERENCES users (username)
-            );
-            CREATE INDEX IF NOT EXISTS contacts_user1_index 
-            ON contacts (user1);
-            CREATE INDEX IF NOT EXISTS contacts_user2_index 
-            ON contacts (user2);
-
-            CREATE TABLE IF NOT EXISTS msgs (
-            	msg		TEXT,
-            	timestamp	INTEGER,
-            	sender	TEXT,
-            	recv	TEXT,
-                read    INTEGER,
-            	FOREIGN KEY(sender) 
-            		REFERENCES users (username)
-            	FOREIGN KEY(recv) 
-                    REFERENCES users (username)
-            );
-            CREATE INDEX IF NOT EXISTS msgs_sender_index 
-            ON msgs (sender);
-            CREATE INDEX IF NOT EXISTS msgs_recv_index 
-            ON msgs (recv);
-
-            COMMIT;"
-        )
-        .map_err(|e| { debug!("{e}"); actix_web::error::ErrorInternalServerError("Couldn't create the table")})?;
+    let mut conn = pool.get()
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+    prepare(&mut conn)
+        .map_err(|e| { debug!("{e}"); actix_web::error::ErrorInternalServerError("Couldn't prepare the database")})?;
 
     Ok(pool)
 }
 
+/// Creates the tables of a new database and brings it to the latest version
+fn prepare(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let tx = conn.transaction()?;
+    tx.execute_batch(BASE_TABLES)?;
+    tx.commit()?;
+
+    migrate(conn)
+}
+
+fn migrate(conn: &mut Connection) -> Result<(), rusqlite::Error> {
+    let version: usize = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let tx = conn.transaction()?;
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", i + 1)?;
+        tx.commit()?;
+
+        info!("Database migrated to version {}", i + 1);
+    }
+
+    Ok(())
+}
+
 pub async fn execute<T, F>(pool: &Pool, f: F) -> Result<T, actix_web::error::Error>
+where 
+    T: Send + 'static,
+    F: FnOnce(&Transaction) -> Result<T, rusqlite::Error> + Send + 'static,
+{
+    run(pool, TransactionBehavior::Deferred, f).await
+}
+
+/// Like [`execute`], for transactions that read and then write. 
+/// Taking the write lock up front, two of them can't lock each other out
+pub async fn execute_immediate<T, F>(pool: &Pool, f: F) -> Result<T, actix_web::error::Error>
+where 
+    T: Send + 'static,
+    F: FnOnce(&Transaction) -> Result<T, rusqlite::Error> + Send + 'static,
+{
+    run(pool, TransactionBehavior::Immediate, f).await
+}
+
+async fn run<T, F>(pool: &Pool, behavior: TransactionBehavior, f: F) -> Result<T, actix_web::error::Error>
 where 
     T: Send + 'static,
     F: FnOnce(&Transaction) -> Result<T, rusqlite::Error> + Send + 'static,
@@ -82,15 +510,23 @@ where
         .map_err(|_| actix_web::error::ErrorInternalServerError("Internal server error"))?;
 
     web::block(move || {
-        let tx = conn.transaction()?;
-        let res = f(&tx);
+        let tx = conn.transaction_with_behavior(behavior)?;
+        let res = f(&tx)?;
         
-        let _ = tx.commit();
-        return res;
+        tx.commit()?;
+        Ok::<T, rusqlite::Error>(res)
     })
     .await?
     .map_err(|err| {
         debug!("{err}");
         actix_web::error::ErrorInternalServerError("Database error") 
     })
-}
\ No newline at end of file
+}
+
+/// A database of its own for each test, with every migration
+#[cfg(test)]
+pub fn open_in_memory() -> Connection {
+    let mut conn = Connection::open_in_memory().unwrap();
+    prepare(&mut conn).unwrap();
+    conn
+}
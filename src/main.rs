@@ -4,12 +4,12 @@ use actix::Actor;
 use actix_session::{storage::CookieSessionStore, SessionMiddleware};
 use actix_web::{cookie::Key, middleware::Logger, web, App, HttpServer};
 
-use api::{auth::*, contacts::*, msgs::*, user::*};
+use api::{attachments::*, auth::*, contacts::*, drafts::*, export::*, msgs::*, rooms::*, scheduled::*, search::*, stars::*, user::*};
 use db::init_database;
 use dotenv::dotenv;
 use local_ip_address::local_ip;
 use log::{info, LevelFilter};
-use ws::{chat_route, ChatServer};
+use ws::{chat_route, ChatServer, MessagingPolicy};
 
 // This may be very ugly but it's needed for the file bundling
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
@@ -17,6 +17,8 @@ include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 mod db;
 mod ws;
 mod api;
+mod commands;
+mod date;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
@@ -26,14 +28,22 @@ async fn main() -> std::io::Result<()> {
         .filter_level(LevelFilter::Debug)
         .init();
 
+    let pool = init_database().unwrap();
+
+    // Admin commands run on the database and exit without starting the server
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Some((command, args)) = args.split_first() {
+        commands::run(&pool, command, args);
+        return Ok(());
+    }
+
     let port = env::var("PORT").unwrap().parse::<u16>().unwrap();
 
     let ip = local_ip().unwrap();
     info!("Running at http://{ip}:{port}");
 
-    let pool = init_database().unwrap();
-
-    let chat_server = ChatServer {sessions: Default::default(), db: pool.clone() }.start();
+    let policy = MessagingPolicy::from_env();
+    let chat_server = ChatServer::new(pool.clone(), policy.clone()).start();
 
     HttpServer::new(move || {
         let generated = generate();
@@ -43,6 +53,7 @@ async fn main() -> std::io::Result<()> {
         App::new()
             .app_data(web::Data::new(chat_server.clone()))
             .app_data(web::Data::new(pool.clone()))
+            .app_data(web::Data::new(policy.clone()))
             .wrap(
                 SessionMiddleware::builder(CookieSessionStore::default(), session_key)
                 .cookie_secure(false)
@@ -69,11 +80,47 @@ async fn main() -> std::io::Result<()> {
             .service(add_contact)
             .service(delete_contact)
             .service(contact_info)
+            .service(block)
+            .service(unblock)
             
             //MESSAGES
             .service(get_messages)
             .service(get_unread)
             .service(read)
+            .service(edit_message)
+            .service(edit_history)
+            .service(set_disappearing)
+            .service(forward)
+            .service(pin)
+            .service(unpin)
+            .service(get_pins)
+            .service(star)
+            .service(unstar)
+            .service(get_starred)
+            .service(save_draft)
+            .service(clear_draft)
+            .service(export)
+            .service(delete_message)
+            .service(react)
+            .service(unreact)
+            .service(vote)
+            .service(search)
+            .service(upload)
+            .service(download)
+            .service(get_scheduled)
+            .service(schedule)
+            .service(edit_scheduled)
+            .service(cancel_scheduled)
+
+            //ROOMS
+            .service(create_room)
+            .service(room_info)
+            .service(add_member)
+            .service(remove_member)
+            .service(get_room_messages)
+            .service(read_room)
+            .service(get_room_pins)
+            .service(set_room_disappearing)
 
             .service(actix_web_static_files::ResourceFiles::new("/", generated))
     })
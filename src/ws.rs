@@ -4,15 +4,31 @@ use actix::Addr;
 use actix_session::Session;
 use actix_web::{get, web, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
+use serde::Deserialize;
 
 use sessions::WsChatSession;
 
-pub use server::{ChatServer, WsMessage, ReadMessage};
+pub use server::{check_message, Attachment, ChatServer, DeleteMessage, Disappearing, EditMessage, ForwardMessages, MessageStatus, Notice, Pin, PinMessage, Poll, PostNotice, React, SaveDraft, ScheduledMessage, SetDisappearing, Vote, WsMessage, ReadMessage, ReadRoom, MSG_COLUMNS, MSG_COLUMN_COUNT, SCHEDULED_COLUMNS};
+pub use events::{ChatError, NewMessage};
+pub use policy::MessagingPolicy;
 
 use crate::api::auth::validate_session;
 
+mod events;
 mod server;
 mod sessions;
+pub mod policy;
+
+#[derive(Debug, Deserialize)]
+pub struct QuerySync {
+    /// Last event the client has seen, it's sent everything after it before the live events
+    cursor: Option<i64>,
+    /// Any id the client keeps for the tab or device, so what it acknowledges doesn't move the cursor of the others
+    #[serde(default)]
+    device: String,
+}
+
+const MAX_DEVICE_LENGTH: usize = 64;
 
 #[get("/ws")]
 pub async fn chat_route(
@@ -20,14 +36,21 @@ pub async fn chat_route(
     stream: web::Payload,
     srv: web::Data<Addr<ChatServer>>,
     session: Session,
+    query: web::Query<QuerySync>,
 ) -> Result<HttpResponse, actix_web::Error> {
     let user_id = validate_session(&session)?;
+    let query = query.into_inner();
+    if query.device.len() > MAX_DEVICE_LENGTH {
+        return Err(actix_web::error::ErrorBadRequest("The device id is too long"));
+    }
 
     ws::start(
         WsChatSession { 
             name: user_id, 
             hb: Instant::now(), 
-            addr: srv.get_ref().clone() 
+            addr: srv.get_ref().clone(),
+            device: query.device,
+            cursor: query.cursor,
         },
         &req,
         stream,
@@ -0,0 +1,271 @@
+use actix::Message;
+use actix_web::{http::StatusCode, ResponseError};
+use derive_more::Display;
+use serde::{Deserialize, Deserializer, Serialize};
+
+use super::server::{Disappearing, MessageKind, Pin, Poll, Reaction, WsMessage};
+
+/// Everything the server can push to a connected client, tagged by `type`
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+#[rtype(result = "()")]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsEvent {
+    /// A new message for this user, or one they sent from another of their sessions
+    Message(WsMessage),
+    /// A message sent by this user was stored, with the id and time given by the server
+    Ack(WsMessage),
+    /// Messages this user sent to `recv` reached one of their sessions
+    Delivered { recv: String, ids: Vec<i64> },
+    /// `sender` has read these messages, sent to them or to a room. It's this user if they read them from another session
+    Read { 
+        sender: String, 
+        ids: Vec<i64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room: Option<i64>,
+    },
+    /// A message in one of this user's conversations was edited
+    Edited(WsMessage),
+    /// A message was deleted, for everyone or only for this user
+    Deleted { id: i64 },
+    /// Someone reacted to a message, these are all the reactions it has now
+    Reactions { id: i64, reactions: Vec<Reaction> },
+    /// Someone voted in a poll, this is how it stands now
+    Votes { id: i64, poll: Poll },
+    /// A message in one of this user's conversations was pinned
+    Pinned(Pin),
+    /// `sender` unpinned a message in one of this user's conversations
+    Unpinned { id: i64, sender: String },
+    /// The draft of this user in a conversation was saved from one of their sessions, or cleared if there's no `msg`.
+    /// Drafts aren't kept in the event log, clients get them again with the contacts
+    Draft {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        recv: Option<String>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room: Option<i64>,
+        msg: Option<String>,
+        updated: u64,
+    },
+    /// `sender` changed how long the new messages last in their chat with this user, or in a room
+    Disappearing {
+        sender: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        room: Option<i64>,
+        disappearing: Option<Disappearing>,
+    },
+    /// `sender` is writing a message to this user
+    TypingStarted { sender: String },
+    /// `sender` isn't writing to this user anymore
+    TypingStopped { sender: String },
+    /// Something this user asked for was rejected
+    Error { code: ChatError, message: String },
+    /// A message sent by this user wasn't stored, it can be sent again with the same `client_id`
+    Nack { 
+        #[serde(skip_serializing_if = "Option::is_none")]
+        client_id: Option<String>, 
+        code: ChatError, 
+        message: String,
+    },
+    /// Every event missed since the cursor has been sent, the next ones are live.
+    /// If there's a `gap` some of them were too old to be kept
+    Synced { cursor: Option<i64>, gap: bool },
+}
+
+impl WsEvent {
+    /// Events about something stored. They are kept in the event log of the user, so the client can catch up with them
+    pub fn needs_ack(&self) -> bool {
+        matches!(self, 
+            WsEvent::Message(_) | WsEvent::Delivered { .. } | WsEvent::Read { .. } | 
+            WsEvent::Edited(_) | WsEvent::Deleted { .. } | WsEvent::Reactions { .. } | WsEvent::Votes { .. } | 
+            WsEvent::Pinned(_) | WsEvent::Unpinned { .. } | WsEvent::Disappearing { .. }
+        )
+    }
+}
+
+/// An event pushed by the server to a session. 
+/// If it has a `seq` it's in the event log, and the client should acknowledge it so it isn't sent again
+#[derive(Message, Serialize, Clone, Debug)]
+#[rtype(result = "()")]
+pub struct Envelope {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub seq: Option<i64>,
+    #[serde(flatten)]
+    pub event: WsEvent,
+}
+
+impl From<ChatError> for WsEvent {
+    fn from(code: ChatError) -> Self {
+        WsEvent::Error { code, message: code.to_string() }
+    }
+}
+
+/// Reasons for the server to reject what a client asked for
+#[derive(Serialize, Deserialize, Display, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ChatError {
+    #[display(fmt = "The message couldn't be understood")]
+    Malformed,
+    #[display(fmt = "That user doesn't exist")]
+    UnknownRecipient,
+    #[display(fmt = "You can't message that user")]
+    Blocked,
+    #[display(fmt = "You can only message your contacts")]
+    NotAContact,
+    #[display(fmt = "You aren't a member of that room")]
+    NotAMember,
+    #[display(fmt = "That message doesn't exist")]
+    NotFound,
+    #[display(fmt = "That message isn't yours")]
+    NotYours,
+    #[display(fmt = "It's too late to delete that message for everyone")]
+    TooLate,
+    #[display(fmt = "That message is from another conversation")]
+    WrongConversation,
+    #[display(fmt = "That isn't a valid reaction")]
+    InvalidReaction,
+    #[display(fmt = "That attachment doesn't exist")]
+    UnknownAttachment,
+    #[display(fmt = "There are too many pinned messages in that conversation")]
+    TooManyPins,
+    #[display(fmt = "There are too many scheduled messages already")]
+    TooManyScheduled,
+    #[display(fmt = "That poll is closed")]
+    PollClosed,
+    #[display(fmt = "Internal server error")]
+    Internal,
+}
+
+// So the same errors can be returned by the REST endpoints that go through the chat server
+impl ResponseError for ChatError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ChatError::Malformed | ChatError::WrongConversation | ChatError::InvalidReaction | ChatError::TooManyPins | ChatError::TooManyScheduled => StatusCode::BAD_REQUEST,
+            ChatError::UnknownRecipient | ChatError::NotFound | ChatError::UnknownAttachment => StatusCode::NOT_FOUND,
+            ChatError::Blocked | ChatError::NotAContact | ChatError::NotAMember | ChatError::NotYours | ChatError::TooLate | ChatError::PollClosed => StatusCode::FORBIDDEN,
+            ChatError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+/// Everything a client can send through the websocket, tagged by `type`
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    Message(Box<NewMessage>),
+    Edit { id: i64, msg: String },
+    Delete { 
+        id: i64, 
+        #[serde(default)]
+        everyone: bool 
+    },
+    React { id: i64, emoji: String },
+    Unreact { id: i64, emoji: String },
+    Pin { id: i64 },
+    Unpin { id: i64 },
+    /// Replaces the votes of the user in a poll, with no options to take them back
+    Vote { id: i64, options: Vec<usize> },
+    TypingStarted { recv: String },
+    TypingStopped { recv: String },
+    /// Every event pushed with a `seq` up to this one has been received
+    Ack { seq: i64 },
+}
+
+/// Old clients send bare messages without a `type`, so they are still accepted.
+/// Anything with a `type` has to be a valid event, it's never taken for a bare message
+#[derive(Debug)]
+pub enum Incoming {
+    Event(ClientEvent),
+    Legacy(Box<NewMessage>),
+}
+
+impl<'de> Deserialize<'de> for Incoming {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let value = serde_json::Value::deserialize(deserializer)?;
+        let incoming = match value.get("type") {
+            Some(_) => serde_json::from_value(value).map(Incoming::Event),
+            None if value.is_object() => serde_json::from_value(value).map(Incoming::Legacy),
+            None => return Err(serde::de::Error::custom("an event has to be an object")),
+        };
+        incoming.map_err(serde::de::Error::custom)
+    }
+}
+
+impl From<Incoming> for ClientEvent {
+    fn from(incoming: Incoming) -> Self {
+        match incoming {
+            Incoming::Event(event) => event,
+            Incoming::Legacy(msg) => ClientEvent::Message(msg),
+        }
+    }
+}
+
+/// A message as written by a client. The sender and time are never taken from the client
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct NewMessage {
+    pub msg: String,
+    #[serde(default)]
+    pub recv: String,
+    #[serde(default)]
+    pub room: Option<i64>,
+    #[serde(default)]
+    pub reply_to: Option<i64>,
+    /// Ids of files uploaded by the sender
+    #[serde(default)]
+    pub attachments: Vec<i64>,
+    /// Chosen by the client so the message is only stored once, no matter how many times it's sent
+    #[serde(default)]
+    pub client_id: Option<String>,
+    /// Only set by the server when it forwards a message, never taken from the client
+    #[serde(skip)]
+    pub forwarded_from: Option<String>,
+    /// The message is a poll. Its question is kept as the text, for the clients that don't know about polls
+    #[serde(default)]
+    pub poll: Option<NewPoll>,
+    /// A location or a contact card. Without a text, the server writes one that describes it
+    #[serde(default)]
+    pub kind: MessageKind,
+}
+
+/// A poll as written by its creator
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct NewPoll {
+    pub question: String,
+    pub options: Vec<String>,
+    #[serde(default)]
+    pub multiple: bool,
+    /// When it stops taking votes, in milliseconds
+    #[serde(default)]
+    pub closes_at: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(text: &str) -> Option<ClientEvent> {
+        serde_json::from_str::<Incoming>(text).ok().map(ClientEvent::from)
+    }
+
+    #[test]
+    fn bare_messages_are_still_messages() {
+        let Some(ClientEvent::Message(msg)) = parse(r#"{"msg":"hi","recv":"bob"}"#) else {
+            panic!("not a message");
+        };
+        assert_eq!((msg.msg.as_str(), msg.recv.as_str()), ("hi", "bob"));
+
+        assert!(matches!(parse(r#"{"type":"message","msg":"hi","recv":"bob"}"#), Some(ClientEvent::Message(_))));
+        assert!(matches!(parse(r#"{"type":"edit","id":1,"msg":"hi"}"#), Some(ClientEvent::Edit { id: 1, .. })));
+    }
+
+    #[test]
+    fn malformed_events_are_not_messages() {
+        for text in [
+            r#"{"type":"edit","id":"x","msg":"hi","recv":"bob"}"#,
+            r#"{"type":"future_thing","msg":"hi","recv":"bob"}"#,
+            r#"{"type":null,"msg":"hi","recv":"bob"}"#,
+            r#"{"recv":"bob"}"#,
+            r#"["hi"]"#,
+        ] {
+            assert!(parse(text).is_none(), "{text}");
+        }
+    }
+}
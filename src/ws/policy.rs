@@ -0,0 +1,216 @@
+use std::{env, time::Duration};
+
+use rusqlite::{params, OptionalExtension, Transaction};
+
+use super::{events::{ChatError, NewMessage}, server::WsMessage};
+
+const DEFAULT_DELETE_WINDOW: Duration = Duration::from_secs(60 * 60);
+const DEFAULT_MAX_ATTACHMENT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// Rules every message has to follow
+#[derive(Debug, Clone, Default)]
+pub struct MessagingPolicy {
+    /// Only allow one-to-one messages to users in the sender's contacts
+    pub contacts_only: bool,
+    /// How long after sending a message it can still be deleted for everyone
+    pub delete_window: Duration,
+    /// Biggest file that can be uploaded, in bytes
+    pub max_attachment_size: u64,
+}
+
+impl MessagingPolicy {
+    pub fn from_env() -> Self {
+        MessagingPolicy {
+            contacts_only: env::var("CONTACTS_ONLY").is_ok_and(|var| var == "true" || var == "1"),
+            delete_window: env::var("DELETE_WINDOW")
+                .ok()
+                .and_then(|var| var.parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_DELETE_WINDOW),
+            max_attachment_size: env::var("MAX_ATTACHMENT_SIZE")
+                .ok()
+                .and_then(|var| var.parse().ok())
+                .unwrap_or(DEFAULT_MAX_ATTACHMENT_SIZE),
+        }
+    }
+
+    pub fn check(&self, conn: &Transaction, sender: &str, msg: &NewMessage) -> Result<Result<(), ChatError>, rusqlite::Error> {
+        if let Some(room) = msg.room {
+            return Ok(is_member(conn, room, sender)?.then_some(()).ok_or(ChatError::NotAMember));
+        }
+
+        self.check_recipient(conn, sender, &msg.recv)
+    }
+
+    /// `sender` can message `recv` in a one-to-one chat
+    pub fn check_recipient(&self, conn: &Transaction, sender: &str, recv: &str) -> Result<Result<(), ChatError>, rusqlite::Error> {
+        if !user_exists(conn, recv)? {
+            return Ok(Err(ChatError::UnknownRecipient));
+        }
+        if is_blocked(conn, sender, recv)? {
+            return Ok(Err(ChatError::Blocked));
+        }
+        if self.contacts_only && !is_contact(conn, sender, recv)? {
+            return Ok(Err(ChatError::NotAContact));
+        }
+
+        Ok(Ok(()))
+    }
+
+    /// `adder` can add `username` to a room with `members`. Being in a room is being messaged by all of them,
+    /// so the adder has to be able to message them and nobody in it can have blocked them, or been blocked by them
+    pub fn check_member(&self, conn: &Transaction, adder: &str, members: &[String], username: &str) -> Result<Result<(), ChatError>, rusqlite::Error> {
+        if let Err(err) = self.check_recipient(conn, adder, username)? {
+            return Ok(Err(err));
+        }
+        for member in members {
+            if is_blocked(conn, member, username)? {
+                return Ok(Err(ChatError::Blocked));
+            }
+        }
+
+        Ok(Ok(()))
+    }
+}
+
+fn exists(conn: &Transaction, query: &str, params: impl rusqlite::Params) -> Result<bool, rusqlite::Error> {
+    conn.query_row(query, params, |_| Ok(()))
+        .optional()
+        .map(|row| row.is_some())
+}
+
+pub fn user_exists(conn: &Transaction, username: &str) -> Result<bool, rusqlite::Error> {
+    exists(conn, "SELECT 1 FROM users WHERE username = ?1", params![username])
+}
+
+pub fn is_member(conn: &Transaction, room: i64, username: &str) -> Result<bool, rusqlite::Error> {
+    exists(conn, "SELECT 1 FROM room_members WHERE room = ?1 AND username = ?2", params![room, username])
+}
+
+pub fn room_members(conn: &Transaction, room: i64) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = conn.prepare("SELECT username FROM room_members WHERE room = ?1")?;
+    let members = stmt.query_map(params![room], |row| row.get(0))?;
+    members.collect()
+}
+
+/// `contact` is in the contacts of `username`
+pub fn is_contact(conn: &Transaction, username: &str, contact: &str) -> Result<bool, rusqlite::Error> {
+    exists(conn, "SELECT 1 FROM contacts WHERE user1 = ?1 AND user2 = ?2", params![username, contact])
+}
+
+/// Any of both users has blocked the other
+pub fn is_blocked(conn: &Transaction, user1: &str, user2: &str) -> Result<bool, rusqlite::Error> {
+    exists(
+        conn,
+        "SELECT 1 FROM blocks WHERE (blocker = ?1 AND blocked = ?2) OR (blocker = ?2 AND blocked = ?1)",
+        params![user1, user2]
+    )
+}
+
+/// `username` takes part in the conversation the message belongs to
+pub fn can_see(conn: &Transaction, username: &str, msg: &WsMessage) -> Result<bool, rusqlite::Error> {
+    match msg.room {
+        Some(room) => is_member(conn, room, username),
+        None => Ok(msg.sender == username || msg.recv == username),
+    }
+}
+
+/// `username` uploaded the file, or can see a message it was sent in
+pub fn can_access_attachment(conn: &Transaction, username: &str, id: i64) -> Result<bool, rusqlite::Error> {
+    let Some(uploader) = conn.query_row(
+        "SELECT uploader FROM attachments WHERE id = ?1",
+        params![id],
+        |row| row.get::<_, String>(0)
+    ).optional()? else {
+        return Ok(false);
+    };
+    if uploader == username {
+        return Ok(true);
+    }
+
+    let mut stmt = conn.prepare("SELECT msg_id FROM msg_attachments WHERE attachment_id = ?1")?;
+    let msgs = stmt.query_map(params![id], |row| row.get(0))?.collect::<Result<Vec<i64>, _>>()?;
+
+    for msg in msgs {
+        if let Some(msg) = WsMessage::load(conn, msg)? {
+            if can_see(conn, username, &msg)? {
+                return Ok(true);
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db;
+
+    use super::*;
+
+    fn users(conn: &mut rusqlite::Connection) {
+        conn.execute_batch(
+            "INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', ''), ('carol', ''), ('dave', '');
+            INSERT INTO rooms (name, owner) VALUES ('r', 'alice');
+            INSERT INTO room_members (room, username) VALUES (1, 'alice'), (1, 'bob');"
+        ).unwrap();
+    }
+
+    #[test]
+    fn contacts_only() {
+        let mut conn = db::open_in_memory();
+        users(&mut conn);
+        conn.execute("INSERT INTO contacts (user1, user2) VALUES ('alice', 'bob')", []).unwrap();
+        let tx = conn.transaction().unwrap();
+        let open = MessagingPolicy::default();
+        let closed = MessagingPolicy { contacts_only: true, ..Default::default() };
+
+        assert_eq!(open.check_recipient(&tx, "alice", "carol").unwrap(), Ok(()));
+        assert_eq!(closed.check_recipient(&tx, "alice", "bob").unwrap(), Ok(()));
+        assert_eq!(closed.check_recipient(&tx, "alice", "carol").unwrap(), Err(ChatError::NotAContact));
+        // Being in someone's contacts doesn't put them in yours
+        assert_eq!(closed.check_recipient(&tx, "bob", "alice").unwrap(), Err(ChatError::NotAContact));
+        assert_eq!(open.check_recipient(&tx, "alice", "nobody").unwrap(), Err(ChatError::UnknownRecipient));
+    }
+
+    #[test]
+    fn blocks() {
+        let mut conn = db::open_in_memory();
+        users(&mut conn);
+        conn.execute_batch(
+            "INSERT INTO contacts (user1, user2) VALUES ('alice', 'bob'), ('bob', 'alice');
+            INSERT INTO blocks (blocker, blocked) VALUES ('bob', 'alice');"
+        ).unwrap();
+        let tx = conn.transaction().unwrap();
+        let policy = MessagingPolicy::default();
+
+        // Both ways, whatever their contacts are
+        assert_eq!(policy.check_recipient(&tx, "alice", "bob").unwrap(), Err(ChatError::Blocked));
+        assert_eq!(policy.check_recipient(&tx, "bob", "alice").unwrap(), Err(ChatError::Blocked));
+        assert_eq!(policy.check_recipient(&tx, "alice", "carol").unwrap(), Ok(()));
+    }
+
+    #[test]
+    fn room_membership() {
+        let mut conn = db::open_in_memory();
+        users(&mut conn);
+        conn.execute("INSERT INTO blocks (blocker, blocked) VALUES ('dave', 'bob')", []).unwrap();
+        let tx = conn.transaction().unwrap();
+        let policy = MessagingPolicy { contacts_only: true, ..Default::default() };
+        let to_room = |sender: &str| {
+            let new = NewMessage { msg: "hi".to_owned(), room: Some(1), ..Default::default() };
+            policy.check(&tx, sender, &new).unwrap()
+        };
+
+        // Members message the room without being each other's contacts
+        assert_eq!(to_room("bob"), Ok(()));
+        assert_eq!(to_room("carol"), Err(ChatError::NotAMember));
+
+        let members = room_members(&tx, 1).unwrap();
+        assert_eq!(policy.check_member(&tx, "alice", &members, "carol").unwrap(), Err(ChatError::NotAContact));
+        tx.execute("INSERT INTO contacts (user1, user2) VALUES ('alice', 'carol'), ('alice', 'dave')", []).unwrap();
+        assert_eq!(policy.check_member(&tx, "alice", &members, "carol").unwrap(), Ok(()));
+        // Someone in the room blocked by them, even if the adder isn't
+        assert_eq!(policy.check_member(&tx, "alice", &members, "dave").unwrap(), Err(ChatError::Blocked));
+    }
+}
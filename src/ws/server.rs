@@ -1,72 +1,831 @@
-use std::{collections::HashMap, time::{SystemTime, UNIX_EPOCH}};
+use std::{collections::{HashMap, HashSet}, sync::LazyLock, time::{Duration, SystemTime, UNIX_EPOCH}};
 
 use actix::prelude::*;
 use actix::{Actor, Context, Handler, Message, Recipient};
-use log::{debug, info, warn};
-use rusqlite::params;
+use log::{debug, error, info, warn};
+use regex::Regex;
+use rusqlite::{params, OptionalExtension, Row, Transaction};
 use serde::{Deserialize, Serialize};
 
-use crate::db::{self, Pool};
+use crate::{api::attachments::{delete_unused, remove_files}, db::{self, Pool}};
 
-#[derive(Message, Deserialize, Serialize, Clone, Debug, Default)]
-#[rtype(result = "()")]
+use super::{events::{ChatError, Envelope, NewMessage, NewPoll, WsEvent}, policy::{can_access_attachment, can_see, is_blocked, is_contact, is_member, room_members, user_exists, MessagingPolicy}};
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct WsMessage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
     pub msg: String,
     pub sender: String,
     pub time: u64,
     pub recv: String,
+    /// Kept for the clients that don't know about the status
     pub read: bool,
+    #[serde(default)]
+    pub status: MessageStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<u64>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<Quote>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub reactions: Vec<Reaction>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// The id the sender gave to the message, so it can match it with the ack
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub client_id: Option<String>,
+    /// When it's going to be deleted for everyone, in a conversation with disappearing messages
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+    /// Who wrote it first, if it's a copy forwarded from another conversation
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub forwarded_from: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub poll: Option<Poll>,
+    #[serde(default, skip_serializing_if = "MessageKind::is_text")]
+    pub kind: MessageKind,
+}
+
+/// What a message holds besides its text. The text always describes it, for the clients that only show text
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum MessageKind {
+    #[default]
+    Text,
+    Location {
+        lat: f64,
+        lon: f64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        label: Option<String>,
+    },
+    /// Another user, with their bio as it was when it was shared
+    Contact {
+        username: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bio: Option<String>,
+    },
+    /// Written by the server about something the sender did, never sent by clients
+    System(Notice),
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "notice", rename_all = "snake_case")]
+pub enum Notice {
+    RoomCreated { name: String },
+    MemberAdded { user: String },
+    /// The sender left the room if it's them
+    MemberRemoved { user: String },
+    PhotoChanged,
+}
+
+/// Where a message is in its way to the receiver. Delivery is only tracked in one-to-one chats
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MessageStatus {
+    #[default]
+    Sent,
+    /// It reached a live session of the receiver
+    Delivered,
+    Read,
+}
+
+/// The message a reply answers to, as it is now. 
+/// If it has been edited the snippet is taken from the latest version, and if it has been deleted there's no snippet at all.
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Quote {
+    pub id: i64,
+    pub sender: String,
+    pub snippet: String,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub edited: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+/// How many users reacted to a message with the same emoji
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: u32,
+}
+
+/// A file uploaded by a user, which can be downloaded by everyone in the conversations it's sent to
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Attachment {
+    pub id: i64,
+    pub mime: String,
+    pub size: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub width: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub height: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    /// Voice notes are sent with their length in milliseconds and how loud they are along it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub duration: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waveform: Option<Vec<u8>>,
+}
+
+/// A message pinned to the top of its conversation
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Pin {
+    #[serde(flatten)]
+    pub msg: WsMessage,
+    pub pinned_by: String,
+    pub pinned_at: u64,
+}
+
+/// How long the new messages of a conversation last before they are deleted for everyone.
+/// In rooms, the ones that last from when they are read start counting the first time someone reads them
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Disappearing {
+    /// In seconds
+    pub after: u64,
+    /// The time starts when the message is read instead of when it's sent
+    #[serde(default)]
+    pub on_read: bool,
+}
+
+/// A question sent as a message, with the votes it has now
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct Poll {
+    pub question: String,
+    pub options: Vec<PollOption>,
+    /// Each user can vote for more than one option
+    #[serde(default)]
+    pub multiple: bool,
+    /// When it stops taking votes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closes_at: Option<u64>,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct PollOption {
+    pub text: String,
+    #[serde(default)]
+    pub votes: u32,
+    /// Who voted for it, the first one first
+    #[serde(default)]
+    pub voters: Vec<String>,
+}
+
+/// A message waiting to be sent by the server at `send_at`, on behalf of its sender
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct ScheduledMessage {
+    pub id: i64,
+    pub msg: String,
+    pub sender: String,
+    /// Empty for the messages to a room
+    pub recv: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reply_to: Option<i64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<i64>,
+    pub send_at: u64,
+    pub created: u64,
+    /// Why it couldn't be sent when its time came. It's tried again if it's edited
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+const SNIPPET_LENGTH: usize = 100;
+const TYPING_TIMEOUT: Duration = Duration::from_secs(6);
+/// Longest text of a message, in characters
+const MAX_MESSAGE_LENGTH: usize = 4096;
+const MAX_EMOJI_LENGTH: usize = 16;
+/// A single emoji: a keycap, a flag, or pictographs with their skin tones joined by zero width joiners
+static EMOJI: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(?x)^(?:
+    [0-9\#*]\x{FE0F}?\x{20E3}
+    | \p{Regional_Indicator}{2}
+    | \x{1F3F4}[\x{E0020}-\x{E007E}]+\x{E007F}
+    | \p{Extended_Pictographic}\x{FE0F}?\p{Emoji_Modifier}?(?:\x{200D}\p{Extended_Pictographic}\x{FE0F}?\p{Emoji_Modifier}?)*
+)$").unwrap());
+const MAX_CLIENT_ID_LENGTH: usize = 64;
+const MAX_ATTACHMENTS: usize = 10;
+const MAX_PINS: usize = 5;
+const MAX_FORWARD: usize = 50;
+const MAX_POLL_OPTIONS: usize = 12;
+const MAX_LOCATION_LABEL_LENGTH: usize = 100;
+const MAX_POLL_TEXT_LENGTH: usize = 300;
+/// How long a poll can stay open
+const MAX_POLL_DURATION: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+/// Events are kept this long for the clients to catch up with them
+const EVENT_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// Longest time a disappearing message can last
+const MAX_DISAPPEAR_AFTER: Duration = Duration::from_secs(365 * 24 * 60 * 60);
+/// How often the expired messages are deleted
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+/// How often the scheduled messages are checked, and so how late they can be sent
+const SCHEDULE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Columns to select from `msgs` so the row can be read with [`WsMessage::from_row`]
+pub const MSG_COLUMNS: &str = "msgs.id, msgs.msg, msgs.sender, msgs.recv, msgs.timestamp, msgs.read_at IS NOT NULL, msgs.room, msgs.edited_at, msgs.deleted, 
+    msgs.reply_to, 
+    (SELECT json_array(sender, msg, edited_at IS NOT NULL, deleted) FROM msgs AS quoted WHERE quoted.id = msgs.reply_to), 
+    (SELECT json_group_array(json_array(emoji, count)) FROM (
+        SELECT emoji, COUNT(*) AS count, MIN(created) AS first FROM reactions 
+        WHERE msg_id = msgs.id 
+        GROUP BY emoji ORDER BY first
+    )), 
+    msgs.delivered_at IS NOT NULL, msgs.client_id, 
+    (SELECT json_group_array(json_object('id', id, 'mime', mime, 'size', size, 'width', width, 'height', height, 'name', name, 'duration', duration, 'waveform', json(waveform))) FROM (
+        SELECT attachments.* FROM msg_attachments 
+        INNER JOIN attachments ON attachments.id = msg_attachments.attachment_id 
+        WHERE msg_attachments.msg_id = msgs.id 
+        ORDER BY msg_attachments.position
+    )), 
+    msgs.expires_at, msgs.forwarded_from, 
+    (SELECT json_object('question', question, 'multiple', json(CASE WHEN multiple THEN 'true' ELSE 'false' END), 'closes_at', closes_at, 'options', json((
+        SELECT json_group_array(json_object('text', options.value, 'voters', json((
+            SELECT json_group_array(username) FROM (
+                SELECT username FROM poll_votes 
+                WHERE poll_votes.msg_id = polls.msg_id AND poll_votes.option = options.key 
+                ORDER BY created, username
+            )
+        )))) FROM json_each(polls.options) AS options
+    ))) FROM polls WHERE polls.msg_id = msgs.id), 
+    msgs.kind";
+/// How many columns there are in [`MSG_COLUMNS`], to select more after them
+pub const MSG_COLUMN_COUNT: usize = 19;
+
+impl WsMessage {
+    pub fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        Ok(WsMessage {
+            id: row.get(0)?,
+            msg: row.get(1)?,
+            sender: row.get(2)?,
+            recv: row.get::<_, Option<String>>(3)?.unwrap_or_default(), // Room messages have no receiver
+            time: row.get(4)?,
+            read: row.get(5)?,
+            status: match (row.get(5)?, row.get(12)?) {
+                (true, _) => MessageStatus::Read,
+                (false, true) => MessageStatus::Delivered,
+                (false, false) => MessageStatus::Sent,
+            },
+            room: row.get(6)?,
+            edited_at: row.get(7)?,
+            deleted: row.get(8)?,
+            reply_to: Quote::from_row(row)?,
+            reactions: Reaction::from_row(row)?,
+            client_id: row.get(13)?,
+            attachments: Attachment::from_row(row)?,
+            expires_at: row.get(15)?,
+            forwarded_from: row.get(16)?,
+            poll: Poll::from_row(row)?,
+            kind: MessageKind::from_row(row)?,
+        })
+    }
+
+    pub fn load(conn: &Transaction, id: i64) -> Result<Option<Self>, rusqlite::Error> {
+        conn.query_row(
+            &format!("SELECT {MSG_COLUMNS} FROM msgs WHERE id = ?1"),
+            params![id],
+            WsMessage::from_row
+        )
+        .optional()
+    }
+
+    /// This message is in the same conversation the new one is being sent to
+    pub fn belongs_to(&self, sender: &str, new: &NewMessage) -> bool {
+        match new.room {
+            Some(room) => self.room == Some(room),
+            None => self.room.is_none() && (
+                (self.sender == sender && self.recv == new.recv) || (self.sender == new.recv && self.recv == sender)
+            ),
+        }
+    }
+
+    /// Every user taking part in the conversation of this message
+    pub fn audience(&self, conn: &Transaction) -> Result<Vec<String>, rusqlite::Error> {
+        match self.room {
+            Some(room) => room_members(conn, room),
+            None => Ok(vec![self.sender.clone(), self.recv.clone()])
+        }
+    }
+}
+
+impl Quote {
+    fn from_row(row: &Row) -> Result<Option<Self>, rusqlite::Error> {
+        let Some(id) = row.get(9)? else {
+            return Ok(None);
+        };
+
+        // The quoted message may be gone for good, which is the same as having been deleted
+        let quoted: Option<String> = row.get(10)?;
+        let (sender, msg, edited, deleted) = quoted
+            .and_then(|quoted| serde_json::from_str::<(String, String, u8, u8)>(&quoted).ok())
+            .unwrap_or((String::new(), String::new(), 0, 1));
+
+        Ok(Some(Quote {
+            id,
+            sender,
+            snippet: if deleted == 0 { msg.chars().take(SNIPPET_LENGTH).collect() } else { String::new() },
+            edited: edited != 0 && deleted == 0,
+            deleted: deleted != 0,
+        }))
+    }
+}
+
+impl Reaction {
+    fn from_row(row: &Row) -> Result<Vec<Self>, rusqlite::Error> {
+        let reactions: String = row.get(11)?;
+        let reactions: Vec<(String, u32)> = serde_json::from_str(&reactions)
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(11, rusqlite::types::Type::Text, Box::new(err)))?;
+
+        Ok(reactions.into_iter().map(|(emoji, count)| Reaction { emoji, count }).collect())
+    }
+
+    /// Emojis can be made of several characters, but not too many
+    pub fn is_valid(emoji: &str) -> bool {
+        emoji.chars().count() <= MAX_EMOJI_LENGTH && EMOJI.is_match(emoji)
+    }
+}
+
+impl Attachment {
+    fn from_row(row: &Row) -> Result<Vec<Self>, rusqlite::Error> {
+        let attachments: String = row.get(14)?;
+        serde_json::from_str(&attachments)
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(14, rusqlite::types::Type::Text, Box::new(err)))
+    }
+}
+
+impl Poll {
+    fn from_row(row: &Row) -> Result<Option<Self>, rusqlite::Error> {
+        let Some(poll) = row.get::<_, Option<String>>(17)? else {
+            return Ok(None);
+        };
+        let mut poll: Poll = serde_json::from_str(&poll)
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(17, rusqlite::types::Type::Text, Box::new(err)))?;
+
+        for option in &mut poll.options {
+            option.votes = option.voters.len() as u32;
+        }
+        Ok(Some(poll))
+    }
+}
+
+impl MessageKind {
+    fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        let Some(kind) = row.get::<_, Option<String>>(18)? else {
+            return Ok(MessageKind::Text);
+        };
+        serde_json::from_str(&kind)
+            .map_err(|err| rusqlite::Error::FromSqlConversionFailure(18, rusqlite::types::Type::Text, Box::new(err)))
+    }
+
+    pub fn is_text(&self) -> bool {
+        *self == MessageKind::Text
+    }
+
+    /// How it's kept in `msgs`, nothing for text
+    pub fn to_sql(&self) -> Result<Option<String>, rusqlite::Error> {
+        if self.is_text() {
+            return Ok(None);
+        }
+        serde_json::to_string(self)
+            .map(Some)
+            .map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))
+    }
+
+    /// The text of a message of this kind sent by `sender`, for the clients that don't know about it
+    pub fn describe(&self, sender: &str) -> Option<String> {
+        Some(match self {
+            MessageKind::Text => return None,
+            MessageKind::Location { lat, lon, label: Some(label) } => format!("Location: {label} ({lat}, {lon})"),
+            MessageKind::Location { lat, lon, label: None } => format!("Location: {lat}, {lon}"),
+            MessageKind::Contact { username, .. } => format!("Contact: {username}"),
+            MessageKind::System(Notice::RoomCreated { name }) => format!("{sender} created the room {name}"),
+            MessageKind::System(Notice::MemberAdded { user }) => format!("{sender} added {user}"),
+            MessageKind::System(Notice::MemberRemoved { user }) if user == sender => format!("{sender} left"),
+            MessageKind::System(Notice::MemberRemoved { user }) => format!("{sender} removed {user}"),
+            MessageKind::System(Notice::PhotoChanged) => format!("{sender} changed their photo"),
+        })
+    }
+}
+
+impl NewPoll {
+    /// It has a question, between two and [`MAX_POLL_OPTIONS`] different options, and closes in the future if it ever does
+    pub fn is_valid(&self, now: u64) -> bool {
+        let is_text = |text: &str| !text.trim().is_empty() && text.chars().count() <= MAX_POLL_TEXT_LENGTH;
+        let distinct = self.options.iter().enumerate().all(|(i, option)| !self.options[..i].contains(option));
+
+        is_text(&self.question)
+            && (2..=MAX_POLL_OPTIONS).contains(&self.options.len())
+            && self.options.iter().all(|option| is_text(option))
+            && distinct
+            && self.closes_at.is_none_or(|closes_at| closes_at > now && closes_at <= now + MAX_POLL_DURATION.as_millis() as u64)
+    }
+}
+
+impl Pin {
+    fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        Ok(Pin {
+            msg: WsMessage::from_row(row)?,
+            pinned_by: row.get(MSG_COLUMN_COUNT)?,
+            pinned_at: row.get(MSG_COLUMN_COUNT + 1)?,
+        })
+    }
+
+    fn load(conn: &Transaction, id: i64) -> Result<Option<Self>, rusqlite::Error> {
+        conn.query_row(
+            &format!(
+                "SELECT {MSG_COLUMNS}, pins.pinned_by, pins.created FROM pins 
+                INNER JOIN msgs ON msgs.id = pins.msg_id 
+                WHERE pins.msg_id = ?1"
+            ),
+            params![id],
+            Pin::from_row
+        )
+        .optional()
+    }
+
+    /// The pins of the chat between both users, or of the room, the oldest first
+    pub fn list(conn: &Transaction, user: &str, recv: &str, room: Option<i64>) -> Result<Vec<Self>, rusqlite::Error> {
+        let mut stmt = conn.prepare(&format!(
+            "SELECT {MSG_COLUMNS}, pins.pinned_by, pins.created FROM pins 
+            INNER JOIN msgs ON msgs.id = pins.msg_id 
+            WHERE msgs.room = ?3 
+            OR (?3 IS NULL AND msgs.room IS NULL AND ((msgs.sender = ?1 AND msgs.recv = ?2) OR (msgs.sender = ?2 AND msgs.recv = ?1))) 
+            ORDER BY pins.created"
+        ))?;
+
+        let pins = stmt.query_map(params![user, recv, room], Pin::from_row)?;
+        pins.collect()
+    }
+}
+
+impl Disappearing {
+    pub fn is_valid(&self) -> bool {
+        (1..=MAX_DISAPPEAR_AFTER.as_secs()).contains(&self.after)
+    }
+
+    /// The setting of the chat between both users, or of the room
+    pub fn load(conn: &Transaction, user: &str, recv: &str, room: Option<i64>) -> Result<Option<Self>, rusqlite::Error> {
+        let read = |row: &Row| Ok((row.get::<_, Option<u64>>(0)?, row.get(1)?));
+        let setting = match room {
+            Some(room) => conn.query_row(
+                "SELECT disappear_after, disappear_on_read FROM rooms WHERE id = ?1",
+                params![room],
+                read
+            ),
+            None => {
+                let (user1, user2) = chat_key(user, recv);
+                conn.query_row(
+                    "SELECT disappear_after, disappear_on_read FROM chat_settings WHERE user1 = ?1 AND user2 = ?2",
+                    params![user1, user2],
+                    read
+                )
+            }
+        }.optional()?;
+
+        Ok(setting.and_then(|(after, on_read)| Some(Disappearing { after: after?, on_read })))
+    }
+}
+
+/// Settings of a chat are kept once for both users, in alphabetical order
+fn chat_key<'a>(user1: &'a str, user2: &'a str) -> (&'a str, &'a str) {
+    if user1 <= user2 { (user1, user2) } else { (user2, user1) }
+}
+
+/// Columns to select from `scheduled_msgs` so the row can be read with [`ScheduledMessage::from_row`]
+pub const SCHEDULED_COLUMNS: &str = "id, msg, sender, recv, room, reply_to, attachments, send_at, created, error";
+
+impl ScheduledMessage {
+    pub fn from_row(row: &Row) -> Result<Self, rusqlite::Error> {
+        let attachments: String = row.get(6)?;
+
+        Ok(ScheduledMessage {
+            id: row.get(0)?,
+            msg: row.get(1)?,
+            sender: row.get(2)?,
+            recv: row.get::<_, Option<String>>(3)?.unwrap_or_default(),
+            room: row.get(4)?,
+            reply_to: row.get(5)?,
+            attachments: serde_json::from_str(&attachments)
+                .map_err(|err| rusqlite::Error::FromSqlConversionFailure(6, rusqlite::types::Type::Text, Box::new(err)))?,
+            send_at: row.get(7)?,
+            created: row.get(8)?,
+            error: row.get(9)?,
+        })
+    }
+}
+
+impl From<ScheduledMessage> for NewMessage {
+    fn from(scheduled: ScheduledMessage) -> Self {
+        NewMessage {
+            msg: scheduled.msg,
+            recv: scheduled.recv,
+            room: scheduled.room,
+            reply_to: scheduled.reply_to,
+            attachments: scheduled.attachments,
+            ..Default::default()
+        }
+    }
+}
+
+/// Result of a change made by a user, which has to be pushed to everyone else involved
+pub struct Outcome<T> {
+    pub reply: T,
+    pub audience: Vec<String>,
+    pub event: WsEvent,
+}
+
+/// Everyone who has to get an event, with its position in their event log if it was stored there
+type Deliveries = Vec<(String, Option<i64>)>;
+
+/// Events stored in a transaction, to be pushed once it's done
+type Pending = Vec<(Deliveries, WsEvent)>;
+
+/// A copy of a message sent to another conversation, with everyone who has to get it
+type Forwarded = (Deliveries, Outcome<WsMessage>);
+
+/// Events a client missed since its cursor
+#[derive(Debug, Default)]
+struct Replay {
+    events: Vec<(i64, WsEvent)>,
+    cursor: Option<i64>,
+    gap: bool,
 }
 
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Connect {
     pub id: String,
-    pub addr: Recipient<WsMessage>,
+    pub addr: Recipient<Envelope>,
+    /// Chosen by the client so each of its devices keeps its own cursor, empty if it doesn't have one
+    pub device: String,
+    /// Last event the client has seen. Without it, the last one the device acknowledged
+    pub cursor: Option<i64>,
 }
 
+/// Sent by a session when it goes away, the user may still be connected with others
 #[derive(Message)]
 #[rtype(result = "()")]
 pub struct Disconnect {
     pub id: String,
+    pub addr: Recipient<Envelope>,
 }
 
+/// A device of `user` received every event up to `seq`, so it won't be sent again to it
 #[derive(Message)]
 #[rtype(result = "()")]
+pub struct Acknowledge {
+    pub user: String,
+    pub device: String,
+    pub seq: i64,
+}
+
+/// Message sent by a client, answered with the stored message once it's persisted
+#[derive(Message)]
+#[rtype(result = "Result<WsMessage, ChatError>")]
+pub struct SendMessage {
+    pub sender: String,
+    pub msg: NewMessage,
+    /// The session that asked for it, which is answered instead of being sent the event
+    pub origin: Option<Recipient<Envelope>>,
+}
+
+/// Replaces the text of a message, only allowed to its sender
+#[derive(Message)]
+#[rtype(result = "Result<WsMessage, ChatError>")]
+pub struct EditMessage {
+    pub editor: String,
+    pub id: i64,
+    pub msg: String,
+    /// The session that asked for it, which is answered instead of being sent the event
+    pub origin: Option<Recipient<Envelope>>,
+}
+
+/// Hides a message from its history for `user`, or turns it into a tombstone for everyone
+#[derive(Message)]
+#[rtype(result = "Result<i64, ChatError>")]
+pub struct DeleteMessage {
+    pub user: String,
+    pub id: i64,
+    pub everyone: bool,
+    /// The session that asked for it, which is answered instead of being sent the event
+    pub origin: Option<Recipient<Envelope>>,
+}
+
+/// Adds or removes the reaction of `user` to a message, answered with all the reactions it has now
+#[derive(Message)]
+#[rtype(result = "Result<(i64, Vec<Reaction>), ChatError>")]
+pub struct React {
+    pub user: String,
+    pub id: i64,
+    pub emoji: String,
+    pub add: bool,
+    /// The session that asked for it, which is answered instead of being sent the event
+    pub origin: Option<Recipient<Envelope>>,
+}
+
+/// Writes a notice about something `sender` did in the room, or in all their one-to-one chats if there's no room.
+/// It's left out of the chats where it's already the last message, like a photo changed again and again.
+/// Answered with the notices written
+#[derive(Message)]
+#[rtype(result = "Result<Vec<WsMessage>, ChatError>")]
+pub struct PostNotice {
+    pub sender: String,
+    pub room: Option<i64>,
+    pub notice: Notice,
+}
+
+/// Replaces the votes of `user` in a poll with the `options` they chose, answered with the poll as it is now
+#[derive(Message)]
+#[rtype(result = "Result<(i64, Poll), ChatError>")]
+pub struct Vote {
+    pub user: String,
+    pub id: i64,
+    pub options: Vec<usize>,
+    /// The session that asked for it, which is answered instead of being sent the event
+    pub origin: Option<Recipient<Envelope>>,
+}
+
+/// Moves forward the position of `reader` in their chat with `writer`, up to a message or to the latest one.
+/// Answered with the messages that have just been read
+#[derive(Message)]
+#[rtype(result = "Result<Vec<i64>, ChatError>")]
 pub struct ReadMessage {
     pub reader: String,
     pub writer: String,
+    pub up_to: Option<i64>,
+}
+
+/// Same as [`ReadMessage`] for a room
+#[derive(Message)]
+#[rtype(result = "Result<Vec<i64>, ChatError>")]
+pub struct ReadRoom {
+    pub reader: String,
+    pub room: i64,
+    pub up_to: Option<i64>,
+}
+
+/// Sends copies of messages from other conversations to `recv` or to a room, in the order they were sent.
+/// Answered with the copies
+#[derive(Message)]
+#[rtype(result = "Result<Vec<WsMessage>, ChatError>")]
+pub struct ForwardMessages {
+    pub user: String,
+    pub ids: Vec<i64>,
+    pub recv: String,
+    pub room: Option<i64>,
+}
+
+/// Keeps what `user` is writing to `recv` or to a room, or forgets it if there's nothing.
+/// Every session of the user is told, so the draft follows them between tabs and devices
+#[derive(Message)]
+#[rtype(result = "Result<(), ChatError>")]
+pub struct SaveDraft {
+    pub user: String,
+    pub recv: String,
+    pub room: Option<i64>,
+    pub msg: Option<String>,
+}
+
+/// Pins a message to its conversation, or unpins it. Answered with the pin if it's pinned now
+#[derive(Message)]
+#[rtype(result = "Result<Option<Pin>, ChatError>")]
+pub struct PinMessage {
+    pub user: String,
+    pub id: i64,
+    pub pin: bool,
+    /// The session that asked for it, which is answered instead of being sent the event
+    pub origin: Option<Recipient<Envelope>>,
+}
+
+/// Changes how long the new messages last in the chat of `user` with `recv`, or in a room. `None` turns it off
+#[derive(Message)]
+#[rtype(result = "Result<Option<Disappearing>, ChatError>")]
+pub struct SetDisappearing {
+    pub user: String,
+    pub recv: String,
+    pub room: Option<i64>,
+    pub disappearing: Option<Disappearing>,
+}
+
+/// Tells the receiver that the sender started or stopped writing to them, nothing is stored
+#[derive(Message)]
+#[rtype(result = "()")]
+pub struct Typing {
+    pub sender: String,
+    pub recv: String,
+    pub typing: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct ChatServer {
-    pub sessions: HashMap<String, Recipient<WsMessage>>,
-    pub db: Pool
+    /// Every live session of each user, who can be connected from several tabs or devices at once
+    pub sessions: HashMap<String, Vec<Recipient<Envelope>>>,
+    /// Live events for the sessions that are still being sent what they missed
+    pub syncing: HashMap<Recipient<Envelope>, Vec<Envelope>>,
+    pub db: Pool,
+    pub policy: MessagingPolicy,
+    /// Timers to stop the typing indicators nobody stopped, by sender and receiver
+    pub typing: HashMap<(String, String), SpawnHandle>,
+}
+
+impl ChatServer {
+    pub fn new(db: Pool, policy: MessagingPolicy) -> Self {
+        ChatServer {
+            sessions: Default::default(),
+            syncing: Default::default(),
+            db,
+            policy,
+            typing: Default::default(),
+        }
+    }
 }
 
 impl Actor for ChatServer {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        ctx.run_interval(PRUNE_INTERVAL, |act, ctx| {
+            let db = act.db.clone();
+            let fut = async move {
+                let oldest = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().saturating_sub(EVENT_RETENTION).as_millis() as u64;
+                let res = db::execute(&db, move |conn| prune_events(conn, oldest)).await;
+
+                match res {
+                    Ok(rows) => debug!("Pruned {rows} old events"),
+                    Err(err) => error!("Couldn't prune the old events: {err}"),
+                }
+            };
+            ctx.spawn(actix::fut::wrap_future(fut));
+        });
+
+        ctx.run_interval(SCHEDULE_INTERVAL, |act, ctx| act.send_scheduled(ctx));
+        ctx.run_interval(SWEEP_INTERVAL, |act, ctx| act.sweep_expired(ctx));
+    }
 }
 
 impl Handler<Connect> for ChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: Connect, ctx: &mut Self::Context) -> Self::Result {
-        info!("{} connected to the server", msg.id);
+    fn handle(&mut self, Connect { id, addr, device, cursor }: Connect, ctx: &mut Self::Context) -> Self::Result {
+        info!("{id} connected to the server");
 
-        let username = msg.id.clone();
+        let username = id.clone();
         let db = self.db.clone();
         let fut = async move {
             db::execute(&db, move |conn| {
                 conn.execute(
                     "UPDATE users SET last_time = ?1 WHERE username = ?2", 
                     params![None::<u64>, username]
-                )
-            }).await.unwrap();
+                )?;
+
+                // Everything sent while the user was away has just reached them
+                let mut stmt = conn.prepare(
+                    "UPDATE msgs SET delivered_at = ?1 
+                    WHERE recv = ?2 AND delivered_at IS NULL 
+                    RETURNING sender, id"
+                )?;
+                let delivered = stmt.query_map(
+                    params![SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64, username], 
+                    |row| Ok((row.get(0)?, row.get(1)?))
+                )?;
+
+                let mut by_sender = HashMap::<String, Vec<i64>>::new();
+                for row in delivered {
+                    let (sender, id) = row?;
+                    by_sender.entry(sender).or_default().push(id);
+                }
+
+                let mut receipts = Vec::new();
+                for (sender, mut ids) in by_sender {
+                    ids.sort_unstable();
+                    let event = WsEvent::Delivered { recv: username.clone(), ids };
+                    receipts.push((record(conn, &[sender], &event)?, event));
+                }
+
+                Ok((receipts, replay(conn, &username, &device, cursor)?))
+            }).await
         };
-        ctx.spawn(actix::fut::wrap_future(fut));
 
-        self.sessions.insert(msg.id, msg.addr);
+        // Live events wait until the session has been sent everything it missed
+        self.syncing.insert(addr.clone(), Vec::new());
+        self.sessions.entry(id.clone()).or_default().push(addr.clone());
+
+        ctx.spawn(actix::fut::wrap_future(fut).map(move |res, act: &mut Self, _| {
+            let (receipts, replay) = res.unwrap_or_else(|err| {
+                error!("Couldn't sync {id}: {err}");
+                (Vec::new(), Replay { gap: true, ..Default::default() })
+            });
+
+            for (deliveries, event) in receipts {
+                act.push(deliveries, &event);
+            }
+            act.finish_sync(id, addr, replay);
+        }));
     }
 }
 
@@ -76,59 +835,1521 @@ impl Handler<Disconnect> for ChatServer {
     fn handle(&mut self, msg: Disconnect, ctx: &mut Self::Context) -> Self::Result {
         info!("{} disconnected from the server", msg.id);
 
+        self.syncing.remove(&msg.addr);
+        let Some(sessions) = self.sessions.get_mut(&msg.id) else {
+            return;
+        };
+        sessions.retain(|addr| *addr != msg.addr);
+
+        // The user is still online until their last session is gone
+        if !sessions.is_empty() {
+            debug!("{} is still connected with another session", msg.id);
+            return;
+        }
+        self.sessions.remove(&msg.id);
+
         let username = msg.id.clone();
         let db = self.db.clone();
         let fut = async move {
-            db::execute(&db, move |conn| {
+            let res = db::execute(&db, move |conn| {
                 conn.execute(
                     "UPDATE users SET last_time = ?1 WHERE username = ?2", 
                     params![SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64, username]
                 )
-            }).await.unwrap();
+            }).await;
+
+            if let Err(err) = res {
+                error!("Couldn't update the last time of {}: {err}", msg.id);
+            }
         };
-        ctx.spawn(actix::fut::wrap_future(fut));
 
-        self.sessions.remove(&msg.id);
+        ctx.spawn(actix::fut::wrap_future(fut));
     }
 }
 
-impl Handler<ReadMessage> for ChatServer { //When a message is read, a message is sent to confirm that read
+impl Handler<Acknowledge> for ChatServer {
     type Result = ();
 
-    fn handle(&mut self, msg: ReadMessage, _: &mut Self::Context) -> Self::Result {
-        warn!("{}", msg.writer);
-        match self.sessions.get(&msg.writer) {
-            Some(addr) => addr.do_send(WsMessage {
-                read: true,
-                sender: msg.reader,
-                ..Default::default()
-            }),
-            None => debug!("Read not propagated!!")
+    fn handle(&mut self, Acknowledge { user, device, seq }: Acknowledge, ctx: &mut Self::Context) -> Self::Result {
+        let db = self.db.clone();
+        let fut = async move {
+            let res = db::execute(&db, move |conn| {
+                // The cursor never goes back
+                conn.execute(
+                    "INSERT INTO device_cursors (username, device, acked_event) VALUES (?1, ?2, ?3) 
+                    ON CONFLICT (username, device) DO UPDATE SET acked_event = MAX(acked_event, excluded.acked_event)",
+                    params![user, device, seq]
+                )
+            }).await;
+
+            if let Err(err) = res {
+                error!("Couldn't acknowledge the events: {err}");
+            }
         };
+        ctx.spawn(actix::fut::wrap_future(fut));
+    }
+}
+
+impl Handler<ReadMessage> for ChatServer { //When a message is read, a message is sent to confirm that read
+    type Result = ResponseActFuture<Self, Result<Vec<i64>, ChatError>>;
+
+    fn handle(&mut self, ReadMessage { reader, writer, up_to }: ReadMessage, _: &mut Self::Context) -> Self::Result {
+        debug!("{reader} read {writer} up to {up_to:?}");
+
+        self.dispatch(None, move |conn| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            let mut stmt = conn.prepare(
+                "UPDATE msgs SET read_at = ?1, delivered_at = COALESCE(delivered_at, ?1), expires_at = COALESCE(expires_at, ?1 + disappear_after * 1000) 
+                WHERE recv = ?2 AND sender = ?3 AND read_at IS NULL AND id <= ?4 
+                RETURNING id"
+            )?;
+            let mut ids = stmt.query_map(params![now, reader, writer, up_to.unwrap_or(i64::MAX)], |row| row.get(0))?
+                .collect::<Result<Vec<i64>, _>>()?;
+            ids.sort_unstable();
+
+            // The other sessions of the reader stop showing them as unread
+            Ok(Ok(Outcome {
+                audience: if ids.is_empty() { Vec::new() } else { vec![writer, reader.clone()] },
+                event: WsEvent::Read { sender: reader, ids: ids.clone(), room: None },
+                reply: ids,
+            }))
+        })
+    }
+}
+
+impl Handler<ReadRoom> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<Vec<i64>, ChatError>>;
+
+    fn handle(&mut self, ReadRoom { reader, room, up_to }: ReadRoom, _: &mut Self::Context) -> Self::Result {
+        self.dispatch(None, move |conn| {
+            let Some(last_read) = conn.query_row(
+                "SELECT last_read FROM room_members WHERE room = ?1 AND username = ?2",
+                params![room, reader],
+                |row| row.get::<_, i64>(0)
+            ).optional()? else {
+                return Ok(Err(ChatError::NotAMember));
+            };
+
+            let mut stmt = conn.prepare(
+                "SELECT id FROM msgs 
+                WHERE room = ?1 AND id > ?2 AND id <= ?3 AND sender != ?4 
+                ORDER BY id"
+            )?;
+            let ids = stmt.query_map(params![room, last_read, up_to.unwrap_or(i64::MAX), reader], |row| row.get(0))?
+                .collect::<Result<Vec<i64>, _>>()?;
+
+            // The position never goes back
+            if let Some(last) = ids.last() {
+                conn.execute(
+                    "UPDATE room_members SET last_read = ?1 WHERE room = ?2 AND username = ?3",
+                    params![last, room, reader]
+                )?;
+                conn.execute(
+                    "UPDATE msgs SET expires_at = COALESCE(expires_at, ?1 + disappear_after * 1000) 
+                    WHERE room = ?2 AND id > ?3 AND id <= ?4 AND sender != ?5",
+                    params![SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64, room, last_read, last, reader]
+                )?;
+            }
+
+            Ok(Ok(Outcome {
+                audience: if ids.is_empty() { Vec::new() } else { room_members(conn, room)? },
+                event: WsEvent::Read { sender: reader, ids: ids.clone(), room: Some(room) },
+                reply: ids,
+            }))
+        })
     }
 }
  
-impl Handler<WsMessage> for ChatServer {
-    type Result = ();
+impl Handler<SendMessage> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<WsMessage, ChatError>>;
     
-    fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) -> Self::Result {
-        warn!("Sent message {msg:?}");
+    fn handle(&mut self, SendMessage { sender, msg, origin }: SendMessage, ctx: &mut Self::Context) -> Self::Result {
+        debug!("Sent message {msg:?} from {sender}");
+
+        // The message itself tells the receiver the sender isn't typing anymore
+        if let Some(handle) = self.typing.remove(&(sender.clone(), msg.recv.clone())) {
+            ctx.cancel_future(handle);
+        }
+
+        let policy = self.policy.clone();
+        let online = msg.room.is_none() && self.sessions.contains_key(&msg.recv);
+        self.dispatch(origin, move |conn| store_message(conn, &policy, sender, msg, online))
+    }    
+}
+
+impl Handler<ForwardMessages> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<Vec<WsMessage>, ChatError>>;
 
-        match self.sessions.get(&msg.recv) {
-            Some(addr) => addr.do_send(msg.clone()),
-            None => debug!("Message not propagated!!")
+    fn handle(&mut self, ForwardMessages { user, ids, recv, room }: ForwardMessages, _: &mut Self::Context) -> Self::Result {
+        let policy = self.policy.clone();
+        let online = room.is_none() && self.sessions.contains_key(&recv);
+
+        let db = self.db.clone();
+        let fut = async move {
+            db::execute_immediate(&db, move |conn| forward_messages(conn, &policy, &user, ids, &recv, room, online)).await
         };
 
+        Box::pin(actix::fut::wrap_future(fut).map(move |res, act: &mut Self, _| {
+            let sent = res.unwrap_or(Err(ChatError::Internal))?;
+            Ok(sent.into_iter()
+                .map(|(deliveries, outcome)| {
+                    act.push(deliveries, &outcome.event);
+                    outcome.reply
+                })
+                .collect())
+        }))
+    }
+}
+
+impl Handler<PostNotice> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<Vec<WsMessage>, ChatError>>;
+
+    fn handle(&mut self, PostNotice { sender, room, notice }: PostNotice, _: &mut Self::Context) -> Self::Result {
         let db = self.db.clone();
         let fut = async move {
-            db::execute(&db, move |conn| {
+            db::execute_immediate(&db, move |conn| {
+                let chats = match room {
+                    Some(room) => vec![(None, Some(room))],
+                    None => chat_partners(conn, &sender)?.into_iter().map(|partner| (Some(partner), None)).collect(),
+                };
+
+                let mut posted = Vec::new();
+                for (recv, room) in chats {
+                    if repeats_last(conn, &sender, recv.as_deref(), room, &notice)? {
+                        continue;
+                    }
+                    let msg = store_notice(conn, &sender, recv.as_deref(), room, notice.clone())?;
+
+                    // Whoever was removed is told too, though they can't see the room anymore
+                    let mut audience = msg.audience(conn)?;
+                    if let Notice::MemberRemoved { user } = &notice {
+                        if !audience.contains(user) {
+                            audience.push(user.clone());
+                        }
+                    }
+
+                    let event = WsEvent::Message(msg.clone());
+                    posted.push((record(conn, &audience, &event)?, event, msg));
+                }
+
+                Ok(posted)
+            }).await
+        };
+
+        Box::pin(actix::fut::wrap_future(fut).map(move |res, act: &mut Self, _| {
+            let posted = res.map_err(|_| ChatError::Internal)?;
+            Ok(posted.into_iter()
+                .map(|(deliveries, event, msg)| {
+                    act.push(deliveries, &event);
+                    msg
+                })
+                .collect())
+        }))
+    }
+}
+
+impl Handler<EditMessage> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<WsMessage, ChatError>>;
+
+    fn handle(&mut self, EditMessage { editor, id, msg, origin }: EditMessage, _: &mut Self::Context) -> Self::Result {
+        self.dispatch(origin, move |conn| {
+            let Some(mut edited) = WsMessage::load(conn, id)?.filter(|msg| !msg.deleted) else {
+                return Ok(Err(ChatError::NotFound));
+            };
+            if edited.sender != editor {
+                return Ok(Err(ChatError::NotYours));
+            }
+            // The text of a poll is its question, which can't change under the votes it already has.
+            // Notices say what happened, and that can't change either
+            if edited.poll.is_some() || matches!(edited.kind, MessageKind::System(_)) {
+                return Ok(Err(ChatError::Malformed));
+            }
+
+            // Taking all the text out would delete it, without the window to do it
+            if !is_valid_text(&msg) {
+                return Ok(Err(ChatError::Malformed));
+            }
+
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            conn.execute(
+                "INSERT INTO msg_edits (msg_id, msg, edited_at) VALUES (?1, ?2, ?3)",
+                params![id, edited.msg, now]
+            )?;
+            conn.execute(
+                "UPDATE msgs SET msg = ?1, edited_at = ?2 WHERE id = ?3",
+                params![msg, now, id]
+            )?;
+
+            edited.msg = msg;
+            edited.edited_at = Some(now);
+
+            Ok(Ok(Outcome {
+                audience: edited.audience(conn)?,
+                event: WsEvent::Edited(edited.clone()),
+                reply: edited,
+            }))
+        })
+    }
+}
+
+impl Handler<DeleteMessage> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<i64, ChatError>>;
+
+    fn handle(&mut self, DeleteMessage { user, id, everyone, origin }: DeleteMessage, _: &mut Self::Context) -> Self::Result {
+        let window = self.policy.delete_window;
+
+        self.dispatch(origin, move |conn| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            delete_message(conn, &user, id, everyone, window, now)
+        })
+    }
+}
+
+impl Handler<React> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<(i64, Vec<Reaction>), ChatError>>;
+
+    fn handle(&mut self, React { user, id, emoji, add, origin }: React, _: &mut Self::Context) -> Self::Result {
+        self.dispatch(origin, move |conn| {
+            if !Reaction::is_valid(&emoji) {
+                return Ok(Err(ChatError::InvalidReaction));
+            }
+            match WsMessage::load(conn, id)? {
+                Some(msg) if !msg.deleted && can_see(conn, &user, &msg)? => (),
+                _ => return Ok(Err(ChatError::NotFound)),
+            }
+
+            if add {
                 conn.execute(
-                    "INSERT INTO msgs (sender, recv, msg, timestamp, read) 
-                    VALUES (?1, ?2, ?3, ?4, 0);", 
-                    params![msg.sender, msg.recv, msg.msg, msg.time]
+                    "INSERT OR IGNORE INTO reactions (msg_id, username, emoji, created) VALUES (?1, ?2, ?3, ?4)",
+                    params![id, user, emoji, SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64]
+                )?;
+            } else {
+                conn.execute(
+                    "DELETE FROM reactions WHERE msg_id = ?1 AND username = ?2 AND emoji = ?3",
+                    params![id, user, emoji]
+                )?;
+            }
+
+            let msg = WsMessage::load(conn, id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            Ok(Ok(Outcome {
+                audience: msg.audience(conn)?,
+                event: WsEvent::Reactions { id, reactions: msg.reactions.clone() },
+                reply: (id, msg.reactions),
+            }))
+        })
+    }
+}
+
+impl Handler<Vote> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<(i64, Poll), ChatError>>;
+
+    fn handle(&mut self, Vote { user, id, options, origin }: Vote, _: &mut Self::Context) -> Self::Result {
+        self.dispatch(origin, move |conn| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            vote(conn, &user, id, options, now)
+        })
+    }
+}
+
+impl Handler<SaveDraft> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<(), ChatError>>;
+
+    fn handle(&mut self, SaveDraft { user, recv, room, msg }: SaveDraft, _: &mut Self::Context) -> Self::Result {
+        self.dispatch(None, move |conn| {
+            match room {
+                Some(room) if !is_member(conn, room, &user)? => return Ok(Err(ChatError::NotAMember)),
+                None if !user_exists(conn, &recv)? => return Ok(Err(ChatError::UnknownRecipient)),
+                _ => (),
+            }
+            let recv = room.is_none().then_some(recv);
+            let msg = msg.filter(|msg| !msg.trim().is_empty());
+            if msg.as_ref().is_some_and(|msg| !is_valid_text(msg)) {
+                return Ok(Err(ChatError::Malformed));
+            }
+
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            store_draft(conn, &user, recv.as_deref(), room, msg.as_deref(), now)?;
+
+            Ok(Ok(Outcome {
+                audience: vec![user],
+                event: WsEvent::Draft { recv, room, msg, updated: now },
+                reply: (),
+            }))
+        })
+    }
+}
+
+impl Handler<PinMessage> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<Option<Pin>, ChatError>>;
+
+    fn handle(&mut self, PinMessage { user, id, pin, origin }: PinMessage, _: &mut Self::Context) -> Self::Result {
+        self.dispatch(origin, move |conn| {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            pin_message(conn, &user, id, pin, now)
+        })
+    }
+}
+
+impl Handler<SetDisappearing> for ChatServer {
+    type Result = ResponseActFuture<Self, Result<Option<Disappearing>, ChatError>>;
+
+    fn handle(&mut self, SetDisappearing { user, recv, room, disappearing }: SetDisappearing, _: &mut Self::Context) -> Self::Result {
+        self.dispatch(None, move |conn| {
+            if disappearing.is_some_and(|disappearing| !disappearing.is_valid()) {
+                return Ok(Err(ChatError::Malformed));
+            }
+            let (after, on_read) = disappearing.map_or((None, false), |disappearing| (Some(disappearing.after), disappearing.on_read));
+
+            let audience = match room {
+                Some(room) => {
+                    if !is_member(conn, room, &user)? {
+                        return Ok(Err(ChatError::NotAMember));
+                    }
+                    conn.execute(
+                        "UPDATE rooms SET disappear_after = ?1, disappear_on_read = ?2 WHERE id = ?3",
+                        params![after, on_read, room]
+                    )?;
+                    room_members(conn, room)?
+                }
+                None => {
+                    if recv == user || !user_exists(conn, &recv)? {
+                        return Ok(Err(ChatError::UnknownRecipient));
+                    }
+                    if is_blocked(conn, &user, &recv)? {
+                        return Ok(Err(ChatError::Blocked));
+                    }
+                    let (user1, user2) = chat_key(&user, &recv);
+                    conn.execute(
+                        "INSERT INTO chat_settings (user1, user2, disappear_after, disappear_on_read) VALUES (?1, ?2, ?3, ?4) 
+                        ON CONFLICT (user1, user2) DO UPDATE SET disappear_after = excluded.disappear_after, disappear_on_read = excluded.disappear_on_read",
+                        params![user1, user2, after, on_read]
+                    )?;
+                    vec![user.clone(), recv]
+                }
+            };
+
+            Ok(Ok(Outcome {
+                audience,
+                event: WsEvent::Disappearing { sender: user, room, disappearing },
+                reply: disappearing,
+            }))
+        })
+    }
+}
+
+impl Handler<Typing> for ChatServer {
+    type Result = ();
+
+    fn handle(&mut self, Typing { sender, recv, typing }: Typing, ctx: &mut Self::Context) -> Self::Result {
+        if !typing {
+            return self.stop_typing(sender, recv, ctx);
+        }
+
+        let db = self.db.clone();
+        let (s, r) = (sender.clone(), recv.clone());
+        let fut = async move {
+            db::execute(&db, move |conn| Ok(is_contact(conn, &s, &r)? && !is_blocked(conn, &s, &r)?)).await
+        };
+
+        ctx.spawn(actix::fut::wrap_future(fut).map(move |allowed, act: &mut Self, ctx| {
+            if !matches!(allowed, Ok(true)) {
+                debug!("{sender} can't tell {recv} they're typing");
+                return;
+            }
+
+            // Typing indicators go away on their own if the client doesn't say anything else
+            let (s, r) = (sender.clone(), recv.clone());
+            let handle = ctx.run_later(TYPING_TIMEOUT, move |act, ctx| act.stop_typing(s, r, ctx));
+
+            match act.typing.insert((sender.clone(), recv.clone()), handle) {
+                Some(old) => { ctx.cancel_future(old); }
+                None => act.push(vec![(recv, None)], &WsEvent::TypingStarted { sender: sender.clone() }),
+            }
+        }));
+    }
+}
+
+impl ChatServer {
+    fn stop_typing(&mut self, sender: String, recv: String, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.typing.remove(&(sender.clone(), recv.clone())) {
+            ctx.cancel_future(handle);
+            self.push(vec![(recv, None)], &WsEvent::TypingStopped { sender });
+        }
+    }
+
+    /// Runs `f` in a transaction and, if it goes well, pushes its event to every session involved but the `origin`
+    fn dispatch<T, F>(&self, origin: Option<Recipient<Envelope>>, f: F) -> ResponseActFuture<Self, Result<T, ChatError>>
+    where 
+        T: Send + 'static,
+        F: FnOnce(&Transaction) -> Result<Result<Outcome<T>, ChatError>, rusqlite::Error> + Send + 'static,
+    {
+        let db = self.db.clone();
+        let fut = async move {
+            db::execute_immediate(&db, move |conn| {
+                let outcome = match f(conn)? {
+                    Ok(outcome) => outcome,
+                    Err(err) => return Ok(Err(err)),
+                };
+                let deliveries = record(conn, &outcome.audience, &outcome.event)?;
+                Ok(Ok((outcome, deliveries)))
+            }).await
+        };
+
+        Box::pin(actix::fut::wrap_future(fut).map(move |res, act: &mut Self, _| {
+            let (outcome, deliveries) = res.unwrap_or(Err(ChatError::Internal))?;
+            act.push_except(deliveries, &outcome.event, origin.as_ref());
+            Ok(outcome.reply)
+        }))
+    }
+
+    /// Sends the scheduled messages whose time has come, as if their senders had just sent them.
+    /// The ones that go against the policy by then are kept with the reason, for their senders to see
+    fn send_scheduled(&mut self, ctx: &mut Context<Self>) {
+        let db = self.db.clone();
+        let policy = self.policy.clone();
+        let online: HashSet<String> = self.sessions.keys().cloned().collect();
+
+        let fut = async move {
+            // Most of the time nothing is due, which can be seen without taking the write lock
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            let due: bool = db::execute(&db, move |conn| {
+                conn.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM scheduled_msgs WHERE send_at <= ?1 AND error IS NULL)",
+                    params![now],
+                    |row| row.get(0)
                 )
-            }).await.unwrap();
+            }).await?;
+            if !due {
+                return Ok(Vec::new());
+            }
+
+            db::execute_immediate(&db, move |conn| send_due(conn, &policy, &online, now)).await
         };
-        ctx.spawn(actix::fut::wrap_future(fut));
-    }    
-}
\ No newline at end of file
+
+        ctx.spawn(actix::fut::wrap_future(fut).map(|res, act: &mut Self, _| {
+            match res {
+                Ok(sent) => sent.into_iter().for_each(|(deliveries, event)| act.push(deliveries, &event)),
+                Err(err) => error!("Couldn't send the scheduled messages: {err}"),
+            }
+        }));
+    }
+
+    /// Deletes for everyone the messages that have expired, with the files that aren't sent in any other message
+    fn sweep_expired(&mut self, ctx: &mut Context<Self>) {
+        let db = self.db.clone();
+
+        let fut = async move {
+            // Most of the time nothing has expired, which can be seen without taking the write lock
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+            let expired: bool = db::execute(&db, move |conn| {
+                conn.query_row("SELECT EXISTS (SELECT 1 FROM msgs WHERE expires_at <= ?1)", params![now], |row| row.get(0))
+            }).await?;
+            if !expired {
+                return Ok(Vec::new());
+            }
+
+            let deleted = db::execute_immediate(&db, move |conn| {
+                let (deleted, files) = forget_expired(conn, now)?;
+
+                // Still holding the write lock, so no upload of the same file can start using it in between
+                remove_files(files);
+                Ok(deleted)
+            }).await?;
+
+            Ok::<_, actix_web::Error>(deleted)
+        };
+
+        ctx.spawn(actix::fut::wrap_future(fut).map(|res, act: &mut Self, _| {
+            match res {
+                Ok(deleted) => deleted.into_iter().for_each(|(deliveries, event)| act.push(deliveries, &event)),
+                Err(err) => error!("Couldn't delete the expired messages: {err}"),
+            }
+        }));
+    }
+
+    /// Sends the event to everyone in `deliveries` who is connected.
+    /// The sessions still catching up get it after the events they missed
+    fn push(&mut self, deliveries: Deliveries, event: &WsEvent) {
+        self.push_except(deliveries, event, None);
+    }
+
+    /// Same as [`ChatServer::push`], but the session that caused the event, which already got an answer, is left out
+    fn push_except(&mut self, deliveries: Deliveries, event: &WsEvent, origin: Option<&Recipient<Envelope>>) {
+        for (user, seq) in deliveries {
+            let envelope = Envelope { seq, event: event.clone() };
+
+            let Some(sessions) = self.sessions.get(&user) else {
+                debug!("Event not propagated to {user}!!");
+                continue;
+            };
+            for addr in sessions.iter().filter(|addr| Some(*addr) != origin) {
+                match self.syncing.get_mut(addr) {
+                    Some(buffer) => buffer.push(envelope.clone()),
+                    None => addr.do_send(envelope.clone()),
+                }
+            }
+        }
+    }
+
+    /// Sends what the client missed, tells it it's up to date and then sends the live events that came meanwhile
+    fn finish_sync(&mut self, user: String, addr: Recipient<Envelope>, replay: Replay) {
+        // The session may have gone away while the events were being read
+        if !self.sessions.get(&user).is_some_and(|sessions| sessions.contains(&addr)) {
+            return;
+        }
+        let buffered = self.syncing.remove(&addr).unwrap_or_default();
+
+        for (seq, event) in replay.events {
+            addr.do_send(Envelope { seq: Some(seq), event });
+        }
+        addr.do_send(Envelope { seq: None, event: WsEvent::Synced { cursor: replay.cursor, gap: replay.gap } });
+
+        buffered.into_iter()
+            .filter(|envelope| envelope.seq.is_none() || envelope.seq > replay.cursor)
+            .for_each(|envelope| addr.do_send(envelope));
+    }
+}
+
+/// Keeps the event in the log of everyone in `audience`, if it's about something stored.
+/// Whoever caused it is in the audience too, for their other sessions.
+/// Returns who has to get it and its position in their log
+fn record(conn: &Transaction, audience: &[String], event: &WsEvent) -> Result<Deliveries, rusqlite::Error> {
+    let recipients = audience.iter().cloned();
+    if !event.needs_ack() {
+        return Ok(recipients.map(|user| (user, None)).collect());
+    }
+
+    let serialized = serde_json::to_string(event).map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    let mut stmt = conn.prepare("INSERT INTO events (username, event, created) VALUES (?1, ?2, ?3) RETURNING (id)")?;
+
+    recipients
+        .map(|user| {
+            let seq = stmt.query_row(params![user, serialized, now], |row| row.get(0))?;
+            Ok((user, Some(seq)))
+        })
+        .collect()
+}
+
+/// Leaves only a tombstone of the message, nothing of what was written. Not even in the events clients catch up with
+fn tombstone(conn: &Transaction, id: i64) -> Result<(), rusqlite::Error> {
+    conn.execute("UPDATE msgs SET msg = '', kind = NULL, forwarded_from = NULL, deleted = 1 WHERE id = ?1", params![id])?;
+    forget_events(conn, id)?;
+    conn.execute("DELETE FROM msg_edits WHERE msg_id = ?1", params![id])?;
+    conn.execute("DELETE FROM reactions WHERE msg_id = ?1", params![id])?;
+    conn.execute("DELETE FROM msg_attachments WHERE msg_id = ?1", params![id])?;
+    conn.execute("DELETE FROM pins WHERE msg_id = ?1", params![id])?;
+    conn.execute("DELETE FROM stars WHERE msg_id = ?1", params![id])?;
+    conn.execute("DELETE FROM poll_votes WHERE msg_id = ?1", params![id])?;
+    conn.execute("DELETE FROM polls WHERE msg_id = ?1", params![id])?;
+
+    Ok(())
+}
+
+/// Deletes a message for good, with everything about it. Even the events that had its text are forgotten
+fn forget_message(conn: &Transaction, id: i64) -> Result<(), rusqlite::Error> {
+    // Whatever a tombstone doesn't keep, so the two can't drift apart
+    tombstone(conn, id)?;
+    conn.execute("DELETE FROM hidden_msgs WHERE msg_id = ?1", params![id])?;
+    conn.execute("DELETE FROM msgs WHERE id = ?1", params![id])?;
+
+    Ok(())
+}
+
+/// Deletes every event about a message from the event log, and takes its text out of the quotes in the replies to it
+fn forget_events(conn: &Transaction, id: i64) -> Result<(), rusqlite::Error> {
+    conn.execute("DELETE FROM events WHERE event ->> '$.id' = ?1", params![id])?;
+    conn.execute(
+        "UPDATE events SET event = json_set(json_remove(event, '$.reply_to.edited'), '$.reply_to.snippet', '', '$.reply_to.deleted', json('true')) 
+        WHERE event ->> '$.reply_to.id' = ?1",
+        params![id]
+    )?;
+
+    Ok(())
+}
+
+/// Sends the scheduled messages that are due by `now`. The ones that can't be sent are kept with the reason why
+fn send_due(conn: &Transaction, policy: &MessagingPolicy, online: &HashSet<String>, now: u64) -> Result<Pending, rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {SCHEDULED_COLUMNS} FROM scheduled_msgs 
+        WHERE send_at <= ?1 AND error IS NULL 
+        ORDER BY send_at, id"
+    ))?;
+    let due = stmt.query_map(params![now], ScheduledMessage::from_row)?.collect::<Result<Vec<_>, _>>()?;
+
+    let mut sent = Vec::new();
+    for scheduled in due {
+        let (id, sender) = (scheduled.id, scheduled.sender.clone());
+        let online = scheduled.room.is_none() && online.contains(&scheduled.recv);
+
+        match store_message(conn, policy, sender.clone(), scheduled.into(), online)? {
+            Ok(outcome) => {
+                conn.execute("DELETE FROM scheduled_msgs WHERE id = ?1", params![id])?;
+                sent.push((record(conn, &outcome.audience, &outcome.event)?, outcome.event));
+            }
+            Err(err) => {
+                warn!("Scheduled message {id} from {sender} couldn't be sent: {err}");
+                conn.execute("UPDATE scheduled_msgs SET error = ?1 WHERE id = ?2", params![err.to_string(), id])?;
+            }
+        }
+    }
+
+    Ok(sent)
+}
+
+/// Forgets the messages that expired by `now`, along with the files nothing else uses, which have to be removed
+fn forget_expired(conn: &Transaction, now: u64) -> Result<(Pending, Vec<String>), rusqlite::Error> {
+    let mut stmt = conn.prepare(&format!("SELECT {MSG_COLUMNS} FROM msgs WHERE expires_at <= ?1"))?;
+    let expired = stmt.query_map(params![now], WsMessage::from_row)?.collect::<Result<Vec<_>, _>>()?;
+
+    let (mut deleted, mut files) = (Vec::new(), Vec::new());
+    for msg in expired {
+        let Some(id) = msg.id else {
+            continue;
+        };
+        let audience = msg.audience(conn)?;
+
+        forget_message(conn, id)?;
+        let attachments: Vec<i64> = msg.attachments.iter().map(|attachment| attachment.id).collect();
+        files.extend(delete_unused(conn, &attachments)?);
+
+        let event = WsEvent::Deleted { id };
+        deleted.push((record(conn, &audience, &event)?, event));
+    }
+
+    Ok((deleted, files))
+}
+
+/// Removes the events created before `oldest`
+fn prune_events(conn: &Transaction, oldest: u64) -> Result<usize, rusqlite::Error> {
+    // Each user keeps the last of their events that was pruned, to tell the clients that missed it
+    conn.execute(
+        "UPDATE users SET pruned_event = pruned.id 
+        FROM (SELECT username, MAX(id) AS id FROM events WHERE created < ?1 GROUP BY username) AS pruned 
+        WHERE users.username = pruned.username",
+        params![oldest]
+    )?;
+    conn.execute("DELETE FROM events WHERE created < ?1", params![oldest])
+}
+
+/// Reads every event for `username` after the cursor of the client, or after the last one its device acknowledged.
+/// Devices that never acknowledged anything aren't synced
+fn replay(conn: &Transaction, username: &str, device: &str, cursor: Option<i64>) -> Result<Replay, rusqlite::Error> {
+    let cursor = match cursor {
+        Some(cursor) => Some(cursor),
+        None => conn.query_row(
+            "SELECT acked_event FROM device_cursors WHERE username = ?1 AND device = ?2",
+            params![username, device],
+            |row| row.get(0)
+        ).optional()?,
+    };
+    let Some(cursor) = cursor else {
+        return Ok(Replay::default());
+    };
+
+    // Old events are pruned, if some of them were missed the client has to fetch its conversations again
+    let pruned: Option<i64> = conn.query_row(
+        "SELECT pruned_event FROM users WHERE username = ?1",
+        params![username],
+        |row| row.get(0)
+    ).optional()?.flatten();
+    let gap = pruned.is_some_and(|pruned| cursor < pruned);
+
+    let mut stmt = conn.prepare("SELECT id, event FROM events WHERE username = ?1 AND id > ?2 ORDER BY id")?;
+    let rows = stmt.query_map(params![username, cursor], |row| Ok((row.get(0)?, row.get::<_, String>(1)?)))?;
+
+    let mut events = Vec::new();
+    for row in rows {
+        let (id, event) = row?;
+        match serde_json::from_str(&event) {
+            Ok(event) => events.push((id, event)),
+            Err(err) => warn!("Event {id} couldn't be read: {err}"),
+        }
+    }
+
+    Ok(Replay {
+        cursor: events.last().map(|(id, _)| *id).or(Some(cursor)),
+        events,
+        gap,
+    })
+}
+
+/// Checks that `sender` can send the message: it follows the policy, the reply is to the same conversation
+/// and the files are theirs
+pub fn check_message(conn: &Transaction, policy: &MessagingPolicy, sender: &str, new: &NewMessage) -> Result<Result<(), ChatError>, rusqlite::Error> {
+    if let Err(err) = policy.check(conn, sender, new)? {
+        return Ok(Err(err));
+    }
+
+    // Replies can only quote messages that are still there, from the same conversation
+    if let Some(reply_to) = new.reply_to {
+        match WsMessage::load(conn, reply_to)?.filter(|quoted| !quoted.deleted) {
+            Some(quoted) if quoted.belongs_to(sender, new) => (),
+            Some(_) => return Ok(Err(ChatError::WrongConversation)),
+            None => return Ok(Err(ChatError::NotFound)),
+        }
+    }
+
+    // Users can only send the files they uploaded, or the ones they were sent
+    if new.attachments.len() > MAX_ATTACHMENTS {
+        return Ok(Err(ChatError::Malformed));
+    }
+    for attachment in &new.attachments {
+        if !can_access_attachment(conn, sender, *attachment)? {
+            return Ok(Err(ChatError::UnknownAttachment));
+        }
+    }
+
+    // Only the messages with something else in them can go without text
+    let has_content = !new.attachments.is_empty() || new.poll.is_some() || !new.kind.is_text();
+    let valid = match new.msg.trim().is_empty() {
+        true => has_content,
+        false => is_valid_text(&new.msg),
+    };
+    if !valid {
+        return Ok(Err(ChatError::Malformed));
+    }
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+    if new.poll.as_ref().is_some_and(|poll| !poll.is_valid(now) || !new.kind.is_text()) {
+        return Ok(Err(ChatError::Malformed));
+    }
+
+    match &new.kind {
+        MessageKind::Text => (),
+        MessageKind::Location { lat, lon, label } => {
+            let valid = (-90.0..=90.0).contains(lat) && (-180.0..=180.0).contains(lon)
+                && label.as_ref().is_none_or(|label| label.chars().count() <= MAX_LOCATION_LABEL_LENGTH);
+            if !valid {
+                return Ok(Err(ChatError::Malformed));
+            }
+        }
+        MessageKind::Contact { username, .. } => {
+            if !user_exists(conn, username)? {
+                return Ok(Err(ChatError::UnknownRecipient));
+            }
+        }
+        // Only the server writes notices
+        MessageKind::System(_) => return Ok(Err(ChatError::Malformed)),
+    }
+
+    Ok(Ok(()))
+}
+
+/// Copies the messages to another conversation, as sent by `user` and written by whoever wrote them first.
+/// Every message is checked before anything is sent, so it's all forwarded or nothing is
+fn forward_messages(conn: &Transaction, policy: &MessagingPolicy, user: &str, ids: Vec<i64>, recv: &str, room: Option<i64>, online: bool) -> Result<Result<Vec<Forwarded>, ChatError>, rusqlite::Error> {
+    if ids.is_empty() || ids.len() > MAX_FORWARD {
+        return Ok(Err(ChatError::Malformed));
+    }
+
+    let mut originals = Vec::new();
+    for id in ids {
+        match WsMessage::load(conn, id)? {
+            Some(msg) if !msg.deleted && can_see(conn, user, &msg)? => originals.push(msg),
+            _ => return Ok(Err(ChatError::NotFound)),
+        }
+    }
+    originals.sort_by_key(|msg| msg.id);
+    originals.dedup_by_key(|msg| msg.id);
+
+    let mut sent = Vec::new();
+    for original in originals {
+        let new = NewMessage {
+            msg: original.msg,
+            recv: recv.to_owned(),
+            room,
+            attachments: original.attachments.iter().map(|attachment| attachment.id).collect(),
+            // Forwarding a copy is still attributed to whoever wrote the message
+            forwarded_from: Some(original.forwarded_from.unwrap_or(original.sender)),
+            // A notice is only about the conversation it was written in, its copy is just the text
+            kind: match original.kind {
+                MessageKind::System(_) => MessageKind::Text,
+                kind => kind,
+            },
+            ..Default::default()
+        };
+
+        let outcome = match store_message(conn, policy, user.to_owned(), new, online)? {
+            Ok(outcome) => outcome,
+            Err(err) => return Ok(Err(err)),
+        };
+        sent.push((record(conn, &outcome.audience, &outcome.event)?, outcome));
+    }
+
+    Ok(Ok(sent))
+}
+
+/// Hides a message from `user`, or deletes it for `everyone` while it's still within the `window` after being sent
+fn delete_message(conn: &Transaction, user: &str, id: i64, everyone: bool, window: Duration, now: u64) -> Result<Result<Outcome<i64>, ChatError>, rusqlite::Error> {
+    let msg = match WsMessage::load(conn, id)? {
+        Some(msg) if can_see(conn, user, &msg)? => msg,
+        _ => return Ok(Err(ChatError::NotFound)),
+    };
+
+    if !everyone {
+        conn.execute(
+            "INSERT OR IGNORE INTO hidden_msgs (msg_id, username) VALUES (?1, ?2)",
+            params![id, user]
+        )?;
+
+        // Only the other sessions of the user are told
+        return Ok(Ok(Outcome { reply: id, audience: vec![user.to_owned()], event: WsEvent::Deleted { id } }));
+    }
+
+    // Notices are written by the server, even if they're about what the sender did
+    if msg.sender != user || matches!(msg.kind, MessageKind::System(_)) {
+        return Ok(Err(ChatError::NotYours));
+    }
+    if now.saturating_sub(msg.time) > window.as_millis() as u64 {
+        return Ok(Err(ChatError::TooLate));
+    }
+
+    tombstone(conn, id)?;
+
+    Ok(Ok(Outcome { reply: id, audience: msg.audience(conn)?, event: WsEvent::Deleted { id } }))
+}
+
+/// Pins a message to its conversation for everyone in it, or unpins it
+fn pin_message(conn: &Transaction, user: &str, id: i64, pin: bool, now: u64) -> Result<Result<Outcome<Option<Pin>>, ChatError>, rusqlite::Error> {
+    let msg = match WsMessage::load(conn, id)? {
+        Some(msg) if !msg.deleted && can_see(conn, user, &msg)? => msg,
+        _ => return Ok(Err(ChatError::NotFound)),
+    };
+
+    if !pin {
+        let unpinned = conn.execute("DELETE FROM pins WHERE msg_id = ?1", params![id])?;
+        return Ok(Ok(Outcome {
+            audience: if unpinned > 0 { msg.audience(conn)? } else { Vec::new() },
+            event: WsEvent::Unpinned { id, sender: user.to_owned() },
+            reply: None,
+        }));
+    }
+
+    // Pinning it again changes nothing
+    if let Some(pinned) = Pin::load(conn, id)? {
+        return Ok(Ok(Outcome { audience: Vec::new(), event: WsEvent::Pinned(pinned.clone()), reply: Some(pinned) }));
+    }
+    if Pin::list(conn, &msg.sender, &msg.recv, msg.room)?.len() >= MAX_PINS {
+        return Ok(Err(ChatError::TooManyPins));
+    }
+
+    conn.execute(
+        "INSERT INTO pins (msg_id, pinned_by, created) VALUES (?1, ?2, ?3)",
+        params![id, user, now]
+    )?;
+    let pinned = Pin::load(conn, id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+
+    Ok(Ok(Outcome {
+        audience: msg.audience(conn)?,
+        event: WsEvent::Pinned(pinned.clone()),
+        reply: Some(pinned),
+    }))
+}
+
+/// Makes `options` the votes of `user` in the poll, none of them to take their votes back
+fn vote(conn: &Transaction, user: &str, id: i64, mut options: Vec<usize>, now: u64) -> Result<Result<Outcome<(i64, Poll)>, ChatError>, rusqlite::Error> {
+    let poll = match WsMessage::load(conn, id)? {
+        Some(msg) if !msg.deleted && can_see(conn, user, &msg)? => msg.poll,
+        _ => None,
+    };
+    let Some(poll) = poll else {
+        return Ok(Err(ChatError::NotFound));
+    };
+
+    if poll.closes_at.is_some_and(|closes_at| closes_at <= now) {
+        return Ok(Err(ChatError::PollClosed));
+    }
+    options.sort_unstable();
+    options.dedup();
+    if options.iter().any(|option| *option >= poll.options.len()) || (!poll.multiple && options.len() > 1) {
+        return Ok(Err(ChatError::Malformed));
+    }
+
+    // The votes that are kept keep their place among the voters
+    let chosen = serde_json::to_string(&options).map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+    conn.execute(
+        "DELETE FROM poll_votes WHERE msg_id = ?1 AND username = ?2 AND option NOT IN (SELECT value FROM json_each(?3))",
+        params![id, user, chosen]
+    )?;
+    let mut stmt = conn.prepare("INSERT OR IGNORE INTO poll_votes (msg_id, option, username, created) VALUES (?1, ?2, ?3, ?4)")?;
+    for option in options {
+        stmt.execute(params![id, option, user, now])?;
+    }
+
+    let msg = WsMessage::load(conn, id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+    let poll = msg.poll.clone().ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+    Ok(Ok(Outcome {
+        audience: msg.audience(conn)?,
+        event: WsEvent::Votes { id, poll: poll.clone() },
+        reply: (id, poll),
+    }))
+}
+
+/// Keeps the draft of `username` for the chat, or forgets it if there's no `msg`
+fn store_draft(conn: &Transaction, username: &str, recv: Option<&str>, room: Option<i64>, msg: Option<&str>, now: u64) -> Result<(), rusqlite::Error> {
+    match msg {
+        Some(msg) => conn.execute(
+            "INSERT INTO drafts (username, recv, room, msg, updated) VALUES (?1, ?2, ?3, ?4, ?5) 
+            ON CONFLICT (username, COALESCE(recv, ''), COALESCE(room, 0)) DO UPDATE SET msg = excluded.msg, updated = excluded.updated",
+            params![username, recv, room, msg, now]
+        )?,
+        None => conn.execute(
+            "DELETE FROM drafts WHERE username = ?1 AND recv IS ?2 AND room IS ?3",
+            params![username, recv, room]
+        )?,
+    };
+
+    Ok(())
+}
+
+/// The text of a message has to say something, but not too much
+pub fn is_valid_text(text: &str) -> bool {
+    !text.trim().is_empty() && text.chars().count() <= MAX_MESSAGE_LENGTH
+}
+
+/// Everyone `username` has a one-to-one chat with, but the ones who blocked them or were blocked
+fn chat_partners(conn: &Transaction, username: &str) -> Result<Vec<String>, rusqlite::Error> {
+    let mut stmt = conn.prepare(
+        "SELECT DISTINCT CASE WHEN sender = ?1 THEN recv ELSE sender END AS partner FROM msgs 
+        WHERE room IS NULL AND (sender = ?1 OR recv = ?1) 
+        AND partner NOT IN (
+            SELECT blocked FROM blocks WHERE blocker = ?1 
+            UNION SELECT blocker FROM blocks WHERE blocked = ?1
+        )"
+    )?;
+
+    let partners = stmt.query_map(params![username], |row| row.get(0))?;
+    partners.collect()
+}
+
+/// Whether the last message in the chat of `sender` with `recv`, or in the room, is already this notice from them
+fn repeats_last(conn: &Transaction, sender: &str, recv: Option<&str>, room: Option<i64>, notice: &Notice) -> Result<bool, rusqlite::Error> {
+    let last: Option<i64> = match room {
+        Some(room) => conn.query_row("SELECT MAX(id) FROM msgs WHERE room = ?1", params![room], |row| row.get(0))?,
+        None => conn.query_row(
+            "SELECT MAX(id) FROM msgs WHERE room IS NULL AND ((sender = ?1 AND recv = ?2) OR (sender = ?2 AND recv = ?1))",
+            params![sender, recv],
+            |row| row.get(0)
+        )?,
+    };
+    let Some(last) = last else {
+        return Ok(false);
+    };
+
+    Ok(WsMessage::load(conn, last)?.is_some_and(|msg| msg.sender == sender && matches!(&msg.kind, MessageKind::System(last) if last == notice)))
+}
+
+/// Stores a notice from the server about `sender`, in their chat with `recv` or in the room.
+/// It isn't something anyone has to answer to: it's stored as read in a one-to-one chat, and never counted as unread in a room
+fn store_notice(conn: &Transaction, sender: &str, recv: Option<&str>, room: Option<i64>, notice: Notice) -> Result<WsMessage, rusqlite::Error> {
+    let kind = MessageKind::System(notice);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+
+    let id = conn.query_row(
+        "INSERT INTO msgs (sender, recv, msg, timestamp, room, delivered_at, read_at, kind) 
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?6, ?7) RETURNING (id);",
+        params![sender, recv, kind.describe(sender), now, room, recv.map(|_| now), kind.to_sql()?],
+        |row| row.get(0)
+    )?;
+
+    WsMessage::load(conn, id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)
+}
+
+/// Stores the message and returns it with its new id, along with every user who has to receive it.
+/// Nothing is stored if the message goes against the policy.
+/// If the receiver is `online` the message is delivered right away.
+fn store_message(conn: &Transaction, policy: &MessagingPolicy, sender: String, new: NewMessage, online: bool) -> Result<Result<Outcome<WsMessage>, ChatError>, rusqlite::Error> {
+    // A message sent again is acknowledged with the stored one, and nobody gets it twice
+    if let Some(client_id) = &new.client_id {
+        if client_id.is_empty() || client_id.len() > MAX_CLIENT_ID_LENGTH {
+            return Ok(Err(ChatError::Malformed));
+        }
+
+        let stored = conn.query_row(
+            "SELECT id FROM msgs WHERE sender = ?1 AND client_id = ?2",
+            params![sender, client_id],
+            |row| row.get(0)
+        ).optional()?;
+
+        if let Some(id) = stored {
+            let msg = WsMessage::load(conn, id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+            return Ok(Ok(Outcome { audience: Vec::new(), event: WsEvent::Message(msg.clone()), reply: msg }));
+        }
+    }
+
+    if let Err(err) = check_message(conn, policy, &sender, &new)? {
+        return Ok(Err(err));
+    }
+
+    // The server is the only one who can tell when a message was sent
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+
+    // Messages that disappear once read only start counting then
+    let (disappear_after, expires_at) = match Disappearing::load(conn, &sender, &new.recv, new.room)? {
+        Some(Disappearing { after, on_read: true }) => (Some(after), None),
+        Some(Disappearing { after, on_read: false }) => (None, Some(now + after * 1000)),
+        None => (None, None),
+    };
+
+    // Contact cards keep the bio the user had when it was shared
+    let kind = match new.kind {
+        MessageKind::Contact { username, .. } => {
+            let bio = conn.query_row("SELECT bio FROM users WHERE username = ?1", params![username], |row| row.get(0))?;
+            MessageKind::Contact { username, bio }
+        }
+        kind => kind,
+    };
+    let text = match (&new.poll, kind.describe(&sender)) {
+        (Some(poll), _) => poll.question.clone(),
+        (None, Some(description)) if new.msg.trim().is_empty() => description,
+        _ => new.msg,
+    };
+
+    let id = conn.query_row(
+        "INSERT INTO msgs (sender, recv, msg, timestamp, room, reply_to, delivered_at, client_id, disappear_after, expires_at, forwarded_from, kind) 
+        VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12) RETURNING (id);", 
+        params![
+            sender, 
+            new.room.is_none().then_some(&new.recv), 
+            text, 
+            now, 
+            new.room,
+            new.reply_to,
+            online.then_some(now),
+            new.client_id,
+            disappear_after,
+            expires_at,
+            new.forwarded_from,
+            kind.to_sql()?
+        ],
+        |row| row.get(0)
+    )?;
+
+    let mut stmt = conn.prepare("INSERT OR IGNORE INTO msg_attachments (msg_id, attachment_id, position) VALUES (?1, ?2, ?3)")?;
+    for (position, attachment) in new.attachments.iter().enumerate() {
+        stmt.execute(params![id, attachment, position])?;
+    }
+
+    if let Some(poll) = &new.poll {
+        let options = serde_json::to_string(&poll.options).map_err(|err| rusqlite::Error::ToSqlConversionFailure(Box::new(err)))?;
+        conn.execute(
+            "INSERT INTO polls (msg_id, question, options, multiple, closes_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![id, poll.question, options, poll.multiple, poll.closes_at]
+        )?;
+    }
+
+    let msg = WsMessage::load(conn, id)?.ok_or(rusqlite::Error::QueryReturnedNoRows)?;
+
+    Ok(Ok(Outcome {
+        audience: msg.audience(conn)?,
+        event: WsEvent::Message(msg.clone()),
+        reply: msg,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emoji_reactions() {
+        for emoji in [
+            "👍", "❤️", "❤", "😂", "©️", "‼️",
+            // Skin tones
+            "👍🏽", "✌🏿",
+            // Keycaps
+            "1️⃣", "#️⃣", "*⃣",
+            // Flags
+            "🇪🇸", "🇺🇦", "🏴󠁧󠁢󠁳󠁣󠁴󠁿", "🏳️‍🌈",
+            // Zero width joiner sequences
+            "👨‍👩‍👧‍👦", "🧑🏽‍💻", "👩‍❤️‍👨",
+        ] {
+            assert!(Reaction::is_valid(emoji), "{emoji} {:?}", emoji.chars().map(|c| c as u32).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn sent_again() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch("INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', '');").unwrap();
+        let tx = conn.transaction().unwrap();
+        let policy = MessagingPolicy::default();
+        let send = |sender: &str, recv: &str, client_id: &str| {
+            let new = NewMessage { msg: "hi".to_owned(), recv: recv.to_owned(), client_id: Some(client_id.to_owned()), ..Default::default() };
+            store_message(&tx, &policy, sender.to_owned(), new, false).unwrap()
+        };
+
+        let first = send("alice", "bob", "1").unwrap();
+        assert_eq!(first.audience, vec!["alice".to_owned(), "bob".to_owned()]);
+        let again = send("alice", "bob", "1").unwrap();
+        assert_eq!(again.reply.id, first.reply.id);
+        assert!(again.audience.is_empty());
+
+        // Client ids only have to be unique for each sender
+        assert_ne!(send("bob", "alice", "1").unwrap().reply.id, first.reply.id);
+        assert_ne!(send("alice", "bob", "2").unwrap().reply.id, first.reply.id);
+
+        for client_id in ["", &"a".repeat(MAX_CLIENT_ID_LENGTH + 1)] {
+            assert_eq!(send("alice", "bob", client_id).err(), Some(ChatError::Malformed));
+        }
+        let sent: i64 = tx.query_row("SELECT COUNT(*) FROM msgs", [], |row| row.get(0)).unwrap();
+        assert_eq!(sent, 3);
+    }
+
+    #[test]
+    fn replayed_events() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch("INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', '');").unwrap();
+        let tx = conn.transaction().unwrap();
+        let alice = ["alice".to_owned()];
+        for (id, created) in [(1, 10), (2, 20), (3, 30)] {
+            record(&tx, &alice, &WsEvent::Deleted { id }).unwrap();
+            tx.execute("UPDATE events SET created = ?1 WHERE id = last_insert_rowid()", params![created]).unwrap();
+        }
+        record(&tx, &["bob".to_owned()], &WsEvent::Deleted { id: 4 }).unwrap();
+        let ids = |replay: &Replay| replay.events.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+
+        let all = replay(&tx, "alice", "phone", Some(0)).unwrap();
+        assert_eq!((ids(&all), all.cursor, all.gap), (vec![1, 2, 3], Some(3), false));
+        let missed = replay(&tx, "alice", "phone", Some(2)).unwrap();
+        assert_eq!((ids(&missed), missed.cursor), (vec![3], Some(3)));
+        let none = replay(&tx, "alice", "phone", Some(3)).unwrap();
+        assert_eq!((ids(&none), none.cursor), (vec![], Some(3)));
+
+        // Without a cursor of its own, the device's last acknowledged event is used, and a new one gets nothing
+        tx.execute("INSERT INTO device_cursors (username, device, acked_event) VALUES ('alice', 'phone', 1)", []).unwrap();
+        assert_eq!(ids(&replay(&tx, "alice", "phone", None).unwrap()), vec![2, 3]);
+        let new = replay(&tx, "alice", "laptop", None).unwrap();
+        assert_eq!((ids(&new), new.cursor, new.gap), (vec![], None, false));
+
+        assert_eq!(prune_events(&tx, 25).unwrap(), 2);
+        let gap = replay(&tx, "alice", "phone", Some(1)).unwrap();
+        assert_eq!((ids(&gap), gap.gap), (vec![3], true));
+        // Only events that were missed make a gap
+        assert!(!replay(&tx, "alice", "phone", Some(2)).unwrap().gap);
+        assert!(!replay(&tx, "bob", "phone", Some(0)).unwrap().gap);
+    }
+
+    #[test]
+    fn scheduled_messages() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch(
+            "INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', ''), ('carol', '');
+            INSERT INTO blocks (blocker, blocked) VALUES ('carol', 'alice');
+            INSERT INTO scheduled_msgs (msg, sender, recv, send_at, created) VALUES 
+                ('later', 'bob', 'alice', 200, 0), 
+                ('first', 'alice', 'bob', 50, 0), 
+                ('second', 'alice', 'bob', 100, 0), 
+                ('blocked', 'alice', 'carol', 100, 0);"
+        ).unwrap();
+        let tx = conn.transaction().unwrap();
+        let online = HashSet::from(["bob".to_owned()]);
+        let sent = |tx: &Transaction, now: u64| -> Vec<(String, MessageStatus)> {
+            send_due(tx, &MessagingPolicy::default(), &online, now).unwrap().into_iter()
+                .map(|(_, event)| match event {
+                    WsEvent::Message(msg) => (msg.msg, msg.status),
+                    event => panic!("{event:?}"),
+                })
+                .collect()
+        };
+
+        assert_eq!(sent(&tx, 10), vec![]);
+        // In the order they were due, delivered to whoever is online
+        assert_eq!(sent(&tx, 100), vec![("first".to_owned(), MessageStatus::Delivered), ("second".to_owned(), MessageStatus::Delivered)]);
+        let error: Option<String> = tx.query_row("SELECT error FROM scheduled_msgs WHERE msg = 'blocked'", [], |row| row.get(0)).unwrap();
+        assert_eq!(error, Some(ChatError::Blocked.to_string()));
+
+        // The ones that failed aren't tried again until they're edited
+        assert_eq!(sent(&tx, 300), vec![("later".to_owned(), MessageStatus::Sent)]);
+        let left: Vec<String> = tx.prepare("SELECT msg FROM scheduled_msgs").unwrap()
+            .query_map([], |row| row.get(0)).unwrap()
+            .collect::<Result<_, _>>().unwrap();
+        assert_eq!(left, vec!["blocked".to_owned()]);
+    }
+
+    #[test]
+    fn deleted_messages_keep_nothing() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch("INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', ''), ('carol', '');").unwrap();
+        let tx = conn.transaction().unwrap();
+
+        let kind = MessageKind::Location { lat: 40.4, lon: -3.7, label: Some("Home".to_owned()) };
+        tx.execute(
+            "INSERT INTO msgs (sender, recv, msg, timestamp, forwarded_from, kind) VALUES ('alice', 'bob', ?1, 0, 'carol', ?2)",
+            params![kind.describe("alice"), kind.to_sql().unwrap()]
+        ).unwrap();
+        tx.execute("INSERT INTO reactions (msg_id, username, emoji, created) VALUES (1, 'bob', '👍', 0)", []).unwrap();
+
+        tombstone(&tx, 1).unwrap();
+        let msg = WsMessage::load(&tx, 1).unwrap().unwrap();
+
+        assert!(msg.deleted);
+        assert_eq!(msg.msg, "");
+        assert_eq!(msg.kind, MessageKind::Text);
+        assert_eq!(msg.forwarded_from, None);
+        assert!(msg.reactions.is_empty());
+    }
+
+    #[test]
+    fn delete_window() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch(
+            "INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', ''), ('carol', '');
+            INSERT INTO msgs (sender, recv, msg, timestamp) VALUES ('alice', 'bob', 'hi', 1000), ('alice', 'bob', 'hey', 1000);"
+        ).unwrap();
+        let tx = conn.transaction().unwrap();
+        let window = Duration::from_secs(60);
+        let delete = |user: &str, id: i64, everyone: bool, now: u64| delete_message(&tx, user, id, everyone, window, now).unwrap().map(|outcome| outcome.audience);
+
+        assert_eq!(delete("carol", 1, false, 1000).err(), Some(ChatError::NotFound));
+        assert_eq!(delete("bob", 1, true, 1000).err(), Some(ChatError::NotYours));
+        assert_eq!(delete("alice", 1, true, 1000 + 60_001).err(), Some(ChatError::TooLate));
+        // Anyone can still hide it from themselves
+        assert_eq!(delete("bob", 1, false, 1000 + 60_001), Ok(vec!["bob".to_owned()]));
+        assert!(!WsMessage::load(&tx, 1).unwrap().unwrap().deleted);
+
+        assert!(delete("alice", 2, true, 1000 + 60_000).is_ok());
+        assert!(WsMessage::load(&tx, 2).unwrap().unwrap().deleted);
+    }
+
+    #[test]
+    fn expired_messages_leave_nothing() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch("INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', '');").unwrap();
+        let tx = conn.transaction().unwrap();
+
+        tx.execute_batch(
+            "INSERT INTO msgs (sender, recv, msg, timestamp, expires_at) VALUES ('alice', 'bob', 'secret', 0, 1);
+            INSERT INTO reactions (msg_id, username, emoji, created) VALUES (1, 'bob', '👍', 0);
+            INSERT INTO stars (username, msg_id, created) VALUES ('bob', 1, 0);
+            INSERT INTO hidden_msgs (msg_id, username) VALUES (1, 'alice');
+            INSERT INTO msg_edits (msg_id, msg, edited_at) VALUES (1, 'old secret', 0);
+            INSERT INTO events (username, event, created) VALUES ('bob', json_object('type', 'message', 'id', 1, 'msg', 'secret'), 0);"
+        ).unwrap();
+
+        forget_message(&tx, 1).unwrap();
+
+        for table in ["msgs", "reactions", "stars", "hidden_msgs", "msg_edits", "events"] {
+            let rows: i64 = tx.query_row(&format!("SELECT COUNT(*) FROM {table}"), [], |row| row.get(0)).unwrap();
+            assert_eq!(rows, 0, "{table}");
+        }
+    }
+
+    #[test]
+    fn expired_messages() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch(
+            "INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', '');
+            INSERT INTO msgs (sender, recv, msg, timestamp, expires_at) VALUES 
+                ('alice', 'bob', 'gone', 0, 100), 
+                ('alice', 'bob', 'kept', 0, 200), 
+                ('alice', 'bob', 'forever', 0, NULL);
+            INSERT INTO attachments (hash, mime, size, uploader, created) VALUES 
+                ('only', 'image/png', 1, 'alice', 0), 
+                ('shared', 'image/png', 1, 'alice', 0);
+            INSERT INTO msg_attachments (msg_id, attachment_id, position) VALUES (1, 1, 0), (1, 2, 1), (2, 2, 0);"
+        ).unwrap();
+        let tx = conn.transaction().unwrap();
+        let msgs = |tx: &Transaction| -> Vec<String> {
+            tx.prepare("SELECT msg FROM msgs ORDER BY id").unwrap()
+                .query_map([], |row| row.get(0)).unwrap()
+                .collect::<Result<_, _>>().unwrap()
+        };
+
+        let (deleted, files) = forget_expired(&tx, 99).unwrap();
+        assert!(deleted.is_empty() && files.is_empty());
+
+        let (deleted, files) = forget_expired(&tx, 100).unwrap();
+        assert_eq!(msgs(&tx), vec!["kept".to_owned(), "forever".to_owned()]);
+        // Both of them are told, even if it was never read
+        let [(deliveries, WsEvent::Deleted { id: 1 })] = deleted.as_slice() else {
+            panic!("{:?}", deleted.iter().map(|(_, event)| event).collect::<Vec<_>>());
+        };
+        assert_eq!(deliveries.iter().map(|(username, _)| username.as_str()).collect::<Vec<_>>(), vec!["alice", "bob"]);
+        // The file still in another message stays
+        assert_eq!(files, vec!["only".to_owned()]);
+
+        let (_, files) = forget_expired(&tx, 1000).unwrap();
+        assert_eq!(msgs(&tx), vec!["forever".to_owned()]);
+        assert_eq!(files, vec!["shared".to_owned()]);
+    }
+
+    #[test]
+    fn pins() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch("INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', ''), ('carol', '');").unwrap();
+        let tx = conn.transaction().unwrap();
+        for i in 0..=MAX_PINS {
+            tx.execute("INSERT INTO msgs (sender, recv, msg, timestamp) VALUES ('alice', 'bob', ?1, 0)", params![i.to_string()]).unwrap();
+        }
+        tx.execute("INSERT INTO msgs (sender, recv, msg, timestamp) VALUES ('alice', 'carol', 'elsewhere', 0)", []).unwrap();
+        let pin = |user: &str, id: i64, pin: bool| pin_message(&tx, user, id, pin, id as u64).unwrap().map(|outcome| outcome.audience);
+        let pinned = || Pin::list(&tx, "bob", "alice", None).unwrap().into_iter().map(|pin| (pin.msg.id.unwrap(), pin.pinned_by)).collect::<Vec<_>>();
+
+        assert_eq!(pin("bob", 1, true), Ok(vec!["alice".to_owned(), "bob".to_owned()]));
+        // Pinning it again tells nobody
+        assert_eq!(pin("alice", 1, true), Ok(vec![]));
+        assert_eq!(pinned(), vec![(1, "bob".to_owned())]);
+        assert_eq!(pin("carol", 2, true), Err(ChatError::NotFound));
+
+        for id in 2..=MAX_PINS as i64 {
+            assert!(pin("alice", id, true).is_ok());
+        }
+        assert_eq!(pin("alice", MAX_PINS as i64 + 1, true), Err(ChatError::TooManyPins));
+        // Other chats have pins of their own
+        assert!(pin("alice", MAX_PINS as i64 + 2, true).is_ok());
+
+        assert_eq!(pin("alice", 1, false), Ok(vec!["alice".to_owned(), "bob".to_owned()]));
+        assert_eq!(pin("alice", 1, false), Ok(vec![]));
+        assert!(pin("alice", MAX_PINS as i64 + 1, true).is_ok());
+
+        tombstone(&tx, 2).unwrap();
+        assert_eq!(pinned().len(), MAX_PINS - 1);
+        assert_eq!(pin("alice", 2, true), Err(ChatError::NotFound));
+    }
+
+    #[test]
+    fn forwarded_messages() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch(
+            "INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', ''), ('carol', ''), ('dave', '');
+            INSERT INTO blocks (blocker, blocked) VALUES ('dave', 'bob');
+            INSERT INTO msgs (sender, recv, msg, timestamp, forwarded_from) VALUES 
+                ('alice', 'bob', 'first', 0, NULL), 
+                ('alice', 'bob', 'copy', 0, 'carol'), 
+                ('alice', 'carol', 'private', 0, NULL);"
+        ).unwrap();
+        let tx = conn.transaction().unwrap();
+        let policy = MessagingPolicy::default();
+        let forward = |ids: Vec<i64>, recv: &str| {
+            forward_messages(&tx, &policy, "bob", ids, recv, None, false).unwrap()
+                .map(|sent| sent.into_iter().map(|(_, outcome)| (outcome.reply.msg, outcome.reply.forwarded_from)).collect::<Vec<_>>())
+        };
+        let count = || tx.query_row("SELECT COUNT(*) FROM msgs", [], |row| row.get::<_, i64>(0)).unwrap();
+
+        // In the order they were sent, once each, still by whoever wrote them
+        assert_eq!(forward(vec![2, 1, 2], "carol"), Ok(vec![
+            ("first".to_owned(), Some("alice".to_owned())),
+            ("copy".to_owned(), Some("carol".to_owned())),
+        ]));
+        assert_eq!(count(), 5);
+
+        // Nothing is sent if any of them can't be
+        assert_eq!(forward(vec![1, 3], "carol"), Err(ChatError::NotFound));
+        assert_eq!(forward(vec![1], "dave"), Err(ChatError::Blocked));
+        assert_eq!(forward(vec![], "carol"), Err(ChatError::Malformed));
+        assert_eq!(forward(vec![1; MAX_FORWARD + 1], "carol"), Err(ChatError::Malformed));
+        assert_eq!(count(), 5);
+    }
+
+    #[test]
+    fn poll_votes() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch(
+            "INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', ''), ('carol', '');
+            INSERT INTO msgs (sender, recv, msg, timestamp) VALUES ('alice', 'bob', 'One?', 0), ('alice', 'bob', 'Many?', 0), ('alice', 'bob', 'Closed?', 0);
+            INSERT INTO polls (msg_id, question, options, multiple, closes_at) VALUES 
+                (1, 'One?', '[\"a\",\"b\",\"c\"]', 0, NULL), 
+                (2, 'Many?', '[\"a\",\"b\",\"c\"]', 1, NULL), 
+                (3, 'Closed?', '[\"a\",\"b\"]', 0, 50);"
+        ).unwrap();
+        let tx = conn.transaction().unwrap();
+        let vote = |user: &str, id: i64, options: Vec<usize>, now: u64| {
+            vote(&tx, user, id, options, now).unwrap()
+                .map(|outcome| outcome.reply.1.options.into_iter().map(|option| (option.votes, option.voters)).collect::<Vec<_>>())
+        };
+        let voters = |names: &[&str]| (names.len() as u32, names.iter().map(|name| name.to_string()).collect::<Vec<_>>());
+
+        assert_eq!(vote("bob", 1, vec![0], 1), Ok(vec![voters(&["bob"]), voters(&[]), voters(&[])]));
+        // A vote for another option replaces it, and none takes it back
+        assert_eq!(vote("bob", 1, vec![1], 2), Ok(vec![voters(&[]), voters(&["bob"]), voters(&[])]));
+        assert_eq!(vote("bob", 1, vec![], 3), Ok(vec![voters(&[]), voters(&[]), voters(&[])]));
+        assert_eq!(vote("bob", 1, vec![0, 1], 4), Err(ChatError::Malformed));
+        assert_eq!(vote("bob", 1, vec![3], 4), Err(ChatError::Malformed));
+
+        assert_eq!(vote("alice", 2, vec![2, 0, 0], 5), Ok(vec![voters(&["alice"]), voters(&[]), voters(&["alice"])]));
+        assert!(vote("bob", 2, vec![0], 6).is_ok());
+        // The votes that are kept keep their place among the voters
+        assert_eq!(vote("alice", 2, vec![0, 1], 7), Ok(vec![voters(&["alice", "bob"]), voters(&["alice"]), voters(&[])]));
+
+        assert!(vote("bob", 3, vec![0], 49).is_ok());
+        assert_eq!(vote("bob", 3, vec![1], 50), Err(ChatError::PollClosed));
+        assert_eq!(vote("carol", 1, vec![0], 1), Err(ChatError::NotFound));
+        tombstone(&tx, 2).unwrap();
+        assert_eq!(vote("bob", 2, vec![0], 8), Err(ChatError::NotFound));
+    }
+
+    #[test]
+    fn message_text() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch("INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', '');").unwrap();
+        let tx = conn.transaction().unwrap();
+        let policy = MessagingPolicy { contacts_only: false, delete_window: Duration::ZERO, max_attachment_size: 0 };
+        let check = |msg: &str, kind: MessageKind| {
+            let new = NewMessage { msg: msg.to_owned(), recv: "bob".to_owned(), kind, ..Default::default() };
+            check_message(&tx, &policy, "alice", &new).unwrap()
+        };
+
+        assert_eq!(check("hi", MessageKind::Text), Ok(()));
+        assert_eq!(check(&"a".repeat(MAX_MESSAGE_LENGTH), MessageKind::Text), Ok(()));
+        assert_eq!(check("", MessageKind::Location { lat: 0.0, lon: 0.0, label: None }), Ok(()));
+
+        for text in ["", " \n\t", &"a".repeat(MAX_MESSAGE_LENGTH + 1)] {
+            assert_eq!(check(text, MessageKind::Text), Err(ChatError::Malformed), "{text:?}");
+        }
+        assert_eq!(check(&"a".repeat(MAX_MESSAGE_LENGTH + 1), MessageKind::Location { lat: 0.0, lon: 0.0, label: None }), Err(ChatError::Malformed));
+    }
+
+    #[test]
+    fn one_draft_per_chat() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch(
+            "INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', '');
+            INSERT INTO rooms (name, owner) VALUES ('r', 'alice');"
+        ).unwrap();
+        let tx = conn.transaction().unwrap();
+        let drafts = |tx: &Transaction| -> Vec<(Option<String>, Option<i64>, String)> {
+            tx.prepare("SELECT recv, room, msg FROM drafts ORDER BY rowid").unwrap()
+                .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?))).unwrap()
+                .collect::<Result<_, _>>().unwrap()
+        };
+
+        store_draft(&tx, "alice", Some("bob"), None, Some("h"), 1).unwrap();
+        store_draft(&tx, "alice", Some("bob"), None, Some("hello"), 2).unwrap();
+        store_draft(&tx, "alice", None, Some(1), Some("everyone"), 3).unwrap();
+        assert_eq!(drafts(&tx), vec![(Some("bob".to_owned()), None, "hello".to_owned()), (None, Some(1), "everyone".to_owned())]);
+
+        store_draft(&tx, "alice", Some("bob"), None, None, 4).unwrap();
+        assert_eq!(drafts(&tx), vec![(None, Some(1), "everyone".to_owned())]);
+
+        // Even if it isn't written through the upsert
+        assert!(tx.execute("INSERT INTO drafts (username, room, msg, updated) VALUES ('alice', 1, 'again', 5)", []).is_err());
+    }
+
+    #[test]
+    fn notices_are_not_repeated() {
+        let mut conn = db::open_in_memory();
+        conn.execute_batch("INSERT INTO users (username, password) VALUES ('alice', ''), ('bob', '');").unwrap();
+        let tx = conn.transaction().unwrap();
+        let repeats = |tx: &Transaction| repeats_last(tx, "alice", Some("bob"), None, &Notice::PhotoChanged).unwrap();
+
+        assert!(!repeats(&tx));
+        store_notice(&tx, "alice", Some("bob"), None, Notice::PhotoChanged).unwrap();
+        assert!(repeats(&tx));
+        assert!(!repeats_last(&tx, "bob", Some("alice"), None, &Notice::PhotoChanged).unwrap());
+
+        tx.execute("INSERT INTO msgs (sender, recv, msg, timestamp) VALUES ('bob', 'alice', 'Nice photo', 0)", []).unwrap();
+        assert!(!repeats(&tx));
+    }
+
+    #[test]
+    fn not_emoji_reactions() {
+        for text in [
+            "", " ", "a", "1", "#", "ok", "<>", "!!!", "<script>", "☺ ", " 👍", "👍👍", "👍a", "🇪",
+            "\u{200D}", "👍\u{200D}", "🏽", "\u{FE0F}", "\u{20E3}", "👨‍👩‍👧‍👦‍👨‍👩‍👧‍👦‍👨‍👩‍👧‍👦",
+        ] {
+            assert!(!Reaction::is_valid(text), "{text:?}");
+        }
+    }
+}
@@ -1,10 +1,10 @@
 use std::time::{Duration, Instant};
 
-use actix::{Actor, ActorContext, Addr, AsyncContext, Handler, Running, StreamHandler};
+use actix::{fut, Actor, ActorContext, ActorFutureExt, Addr, AsyncContext, ContextFutureSpawner, Handler, Message, Running, StreamHandler, WrapFuture};
 use actix_web_actors::ws;
 use log::{debug, info};
 
-use super::server::{ChatServer, Connect, Disconnect, WsMessage};
+use super::{events::{ChatError, ClientEvent, Envelope, Incoming, WsEvent}, server::{Acknowledge, ChatServer, Connect, DeleteMessage, Disconnect, EditMessage, PinMessage, Poll, React, Reaction, SendMessage, Typing, Vote}};
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
@@ -12,7 +12,11 @@ const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
 pub struct WsChatSession {
     pub name: String,
     pub hb: Instant,
-    pub addr: Addr<ChatServer>
+    pub addr: Addr<ChatServer>,
+    /// Chosen by the client, its acknowledged events are kept apart from the other devices of the user
+    pub device: String,
+    /// Last event seen by the client before connecting
+    pub cursor: Option<i64>,
 }   
 
 impl WsChatSession {
@@ -20,7 +24,7 @@ impl WsChatSession {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
                 debug!("Websocket Client heartbeat failed, disconnecting!");
-                act.addr.do_send(Disconnect { id: act.name.clone() });
+                act.addr.do_send(Disconnect { id: act.name.clone(), addr: ctx.address().recipient() });
                 ctx.stop();
                 return;
             }
@@ -28,6 +32,64 @@ impl WsChatSession {
             ctx.ping(b"");
         });
     } 
+
+    fn handle_event(&mut self, event: ClientEvent, ctx: &mut ws::WebsocketContext<Self>) {
+        let name = self.name.clone();
+        let origin = Some(ctx.address().recipient());
+
+        match event {
+            ClientEvent::Message(msg) => {
+                let client_id = msg.client_id.clone();
+                let nack = move |code: ChatError| WsEvent::Nack { client_id, code, message: code.to_string() };
+                self.request(SendMessage { sender: name, msg: *msg, origin }, WsEvent::Ack, nack, ctx)
+            }
+            ClientEvent::Edit { id, msg } => self.request(EditMessage { editor: name, id, msg, origin }, WsEvent::Edited, WsEvent::from, ctx),
+            ClientEvent::Delete { id, everyone } => self.request(DeleteMessage { user: name, id, everyone, origin }, |id| WsEvent::Deleted { id }, WsEvent::from, ctx),
+            ClientEvent::React { id, emoji } => self.request(React { user: name, id, emoji, add: true, origin }, reactions, WsEvent::from, ctx),
+            ClientEvent::Unreact { id, emoji } => self.request(React { user: name, id, emoji, add: false, origin }, reactions, WsEvent::from, ctx),
+            ClientEvent::Pin { id } | ClientEvent::Unpin { id } => {
+                let pin = matches!(event, ClientEvent::Pin { .. });
+                let sender = name.clone();
+                let reply = move |pinned: Option<_>| pinned.map_or(WsEvent::Unpinned { id, sender }, WsEvent::Pinned);
+                self.request(PinMessage { user: name, id, pin, origin }, reply, WsEvent::from, ctx)
+            }
+            ClientEvent::Vote { id, options } => self.request(Vote { user: name, id, options, origin }, votes, WsEvent::from, ctx),
+            ClientEvent::TypingStarted { recv } => self.addr.do_send(Typing { sender: name, recv, typing: true }),
+            ClientEvent::TypingStopped { recv } => self.addr.do_send(Typing { sender: name, recv, typing: false }),
+            ClientEvent::Ack { seq } => self.addr.do_send(Acknowledge { user: name, device: self.device.clone(), seq }),
+        }
+    }
+
+    /// Asks the server and answers the client with `reply`, or with `fail` and the error it got back.
+    /// The session waits for the server so the answers come in the same order the requests were sent.
+    fn request<M, T, R, F>(&self, msg: M, reply: R, fail: F, ctx: &mut ws::WebsocketContext<Self>)
+    where
+        M: Message<Result = Result<T, ChatError>> + Send + 'static,
+        T: Send + 'static,
+        R: FnOnce(T) -> WsEvent + 'static,
+        F: FnOnce(ChatError) -> WsEvent + 'static,
+        ChatServer: Handler<M>,
+    {
+        self.addr.send(msg)
+            .into_actor(self)
+            .then(move |res, _, ctx| {
+                match res {
+                    Ok(Ok(res)) => ctx.notify(reply(res)),
+                    Ok(Err(err)) => ctx.notify(fail(err)),
+                    Err(_) => ctx.notify(fail(ChatError::Internal)),
+                }
+                fut::ready(())
+            })
+            .wait(ctx);
+    }
+}
+
+fn reactions((id, reactions): (i64, Vec<Reaction>)) -> WsEvent {
+    WsEvent::Reactions { id, reactions }
+}
+
+fn votes((id, poll): (i64, Poll)) -> WsEvent {
+    WsEvent::Votes { id, poll }
 }
 
 impl Actor for WsChatSession {
@@ -40,19 +102,30 @@ impl Actor for WsChatSession {
         self.addr.do_send(Connect {
             id: self.name.clone(),
             addr: addr.recipient(),
+            device: self.device.clone(),
+            cursor: self.cursor,
         });
     }
 
-    fn stopping(&mut self, _: &mut Self::Context) -> Running {
-        self.addr.do_send(Disconnect { id: self.name.clone() });
+    fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
+        self.addr.do_send(Disconnect { id: self.name.clone(), addr: ctx.address().recipient() });
         Running::Stop
     }
 }
 
-impl Handler<WsMessage> for WsChatSession {
+impl Handler<WsEvent> for WsChatSession {
     type Result = ();
 
-    fn handle(&mut self, msg: WsMessage, ctx: &mut Self::Context) {
+    /// Events outside of the event log, sent the same as a logged one without its `seq`
+    fn handle(&mut self, event: WsEvent, ctx: &mut Self::Context) {
+        <Self as Handler<Envelope>>::handle(self, Envelope { seq: None, event }, ctx);
+    }
+}
+
+impl Handler<Envelope> for WsChatSession {
+    type Result = ();
+
+    fn handle(&mut self, msg: Envelope, ctx: &mut Self::Context) {
         let serialized = serde_json::to_string(&msg).unwrap();
         debug!("Serialized message: {serialized}");
         
@@ -89,9 +162,17 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WsChatSession {
             ws::Message::Nop => (),
             
             ws::Message::Text(text) => {
-                let msg: WsMessage = serde_json::from_str(&text).unwrap();
-                debug!("Deserialized msg: {msg:?}");
-                self.addr.do_send(msg);
+                let event: ClientEvent = match serde_json::from_str::<Incoming>(&text) {
+                    Ok(event) => event.into(),
+                    Err(err) => {
+                        debug!("Couldn't deserialize {text}: {err}");
+                        ctx.notify(WsEvent::from(ChatError::Malformed));
+                        return;
+                    }
+                };
+                debug!("Deserialized event: {event:?}");
+
+                self.handle_event(event, ctx);
             }
         }
     }
@@ -46,15 +46,22 @@ export default function ChatApp() {
     const navigate = useNavigate();
 
     const onMessage = useCallback(e => {
-        const msg: Message = JSON.parse(e.data);
+        const event = JSON.parse(e.data);
+        if(event.seq !== undefined)
+            e.target.send(JSON.stringify({ type: "ack", seq: event.seq }));
 
-        if(msg.read) { //That means it is a read confirmation
-            if(currentChat?.name === msg.sender) {
+        if(event.type === "read") {
+            if(currentChat?.name === event.sender) {
                 setToRead(true);
             }
             return;
         }
 
+        if(event.type !== "message")
+            return;
+
+        const msg: Message = event;
+
         const chats: [string, IChatPreview][] = Array.from(lastChats, ([k, v]) => {
             if(k === msg.sender)
                 return [k, {
@@ -39,7 +39,7 @@ export default function Chat({socket, currentChat, setCurrentChat, lastChats, se
     const { user } = useAuth();
     const [emojiOpen, setEmojiOpen] = useState(false);
     const [message, setMessage] = useState("");
-    const [page, setPage] = useState(0);
+    const [allLoaded, setAllLoaded] = useState(false);
     const [scrollHeight, setScrollHeight] = useState(0);
 
     const ref = useRef<HTMLDivElement>(null);
@@ -62,19 +62,19 @@ export default function Chat({socket, currentChat, setCurrentChat, lastChats, se
         scrollRef.current?.scrollToBottom(), 
     [currentChat.msgs]);
     const loadMoreMessages = async () => {
-        if(scrollRef.current?.getScrollTop() === 0 && page != -1) {
-            const newMsgs = await loadMessages(currentChat.name, page + 1);
-            if(newMsgs.length === 0) {
-                setPage(-1);
+        const oldest = currentChat.msgs[0]?.id;
+        if(scrollRef.current?.getScrollTop() === 0 && !allLoaded && oldest !== undefined) {
+            const page = await loadMessages(currentChat.name, oldest);
+            if(page.next === undefined || page.next === null)
+                setAllLoaded(true);
+            if(page.msgs.length === 0)
                 return;
-            }
             
-            setPage(page + 1);
             setScrollHeight(scrollRef.current.getScrollHeight())
 
             setCurrentChat({
                 ...currentChat,
-                msgs: newMsgs.concat(currentChat.msgs),
+                msgs: page.msgs.concat(currentChat.msgs),
             })
         }
     }
@@ -106,6 +106,7 @@ export default function Chat({socket, currentChat, setCurrentChat, lastChats, se
             time: new Date().getTime(),
             recv: currentChat.name,
             read: false,
+            client_id: crypto.randomUUID(),
         }
 
         setCurrentChat({
@@ -4,28 +4,36 @@ import { getServerUrl } from "../../App";
 import { CheckCheck } from "lucide-react";
 
 export interface Message {
+    id?: number,
     msg: string,
     time: number,
     sender: string,
     recv: string,
-    read: boolean
+    read: boolean,
+    client_id?: string
 }
 
 export function unixTimeToHour(time: number) {
     return new Date(time).toLocaleTimeString('es-ES', { hour: '2-digit', minute: '2-digit' });
 }
 
+export interface MessagePage {
+    msgs: Message[],
+    next?: number,
+    prev?: number
+}
+
 const MESSAGE_PAGE_SIZE = 20;
-export async function loadMessages(name: string, page: number): Promise<Message[]> {
-    const offset = MESSAGE_PAGE_SIZE * page;
-    const msgResponse = await fetch(getServerUrl(`/msgs/${name}?size=${MESSAGE_PAGE_SIZE}&offset=${offset}`));
+export async function loadMessages(name: string, before?: number): Promise<MessagePage> {
+    const cursor = before === undefined? '' : `&before=${before}`;
+    const msgResponse = await fetch(getServerUrl(`/msgs/${name}?size=${MESSAGE_PAGE_SIZE}${cursor}`));
    
     if(!msgResponse.ok)
         throw Error(await msgResponse.text());
 
-    const msgs: Message[] = await msgResponse.json();
-    msgs.reverse();
-    return msgs;
+    const page: MessagePage = await msgResponse.json();
+    page.msgs.reverse();
+    return page;
 }
 
 export default function ChatMessage({mine, msg}: {
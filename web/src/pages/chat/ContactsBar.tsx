@@ -32,7 +32,7 @@ function Contact({setCurrentChat, chatPreview, lastChats, setLastChats, username
 
             setCurrentChat({
                 ...chatInfo,
-                msgs: await loadMessages(chatPreview.name, 0), //Only the first page
+                msgs: (await loadMessages(chatPreview.name)).msgs, //Only the first page
             });
 
             readMessage(chatPreview.name, lastChats, setLastChats);